@@ -9,8 +9,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let server: Server = serde_json::from_str(&contents)?;
 
-    println!("Listening at {}", server.addr);
-    server.start().await?;
+    let handle = server.spawn().await?;
+    println!("Listening at {:?}", handle.local_addrs());
+    handle.closed().await;
 
     Ok(())
 }
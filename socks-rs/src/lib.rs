@@ -2,6 +2,8 @@
 
 //! # A pure Rust implementation of SOCKS 5 protocol
 //! According to [`RFC 1928`](https://datatracker.ietf.org/doc/html/rfc1928)
+//!
+//! The legacy SOCKS4 and SOCKS4a messages live in [`socks4`].
 
 /// The SOCKS protocol version
 pub const SOCKS_VERSION: u8 = 0x5;
@@ -10,6 +12,7 @@ pub mod auth;
 pub mod establish;
 pub mod reply;
 pub mod request;
+pub mod socks4;
 
 /// `Sendible` trait indicates if a type can be
 /// sendible through the network as raw bytes and
@@ -16,6 +16,10 @@ pub mod command {
     pub const CONNECT: u8 = 0x1;
     pub const BIND: u8 = 0x2;
     pub const UDP_ASSOCIATE: u8 = 0x3;
+
+    // Tor extensions, answered with the address or the name resolved
+    pub const RESOLVE: u8 = 0xf0;
+    pub const RESOLVE_PTR: u8 = 0xf1;
 }
 
 /// The request struct (client-only)
@@ -0,0 +1,215 @@
+//! # SOCKS4
+//! Messages of the [`SOCKS4`](https://www.openssh.com/txt/socks4.protocol)
+//! protocol, along with the domain names of its
+//! [`SOCKS4a`](https://www.openssh.com/txt/socks4a.protocol) extension.
+//!
+//! There is no method negotiation: the request comes first, carrying a
+//! `USERID` the server may look at, but nothing like a password.
+
+use crate::Sendible;
+use std::io;
+
+/// The SOCKS4 protocol version
+pub const SOCKS4_VERSION: u8 = 0x4;
+
+#[allow(missing_docs)]
+pub mod command {
+    pub const CONNECT: u8 = 0x1;
+    pub const BIND: u8 = 0x2;
+}
+
+#[allow(missing_docs)]
+pub mod reply_code {
+    pub const GRANTED: u8 = 0x5a;
+    pub const REJECTED: u8 = 0x5b;
+    pub const IDENTD_UNREACHABLE: u8 = 0x5c;
+    pub const IDENTD_MISMATCH: u8 = 0x5d;
+}
+
+/// The request struct (client-only)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Request<'a> {
+    /// protocol version (0x4)
+    pub version: u8,
+
+    /// command
+    pub cmd: u8,
+
+    /// desired destination port
+    pub dst_port: u16,
+
+    /// desired destination address, `0.0.0.x` with a domain name
+    pub dst_ip: [u8; 4],
+
+    /// user id, without the terminating NUL
+    pub user_id: &'a [u8],
+
+    /// SOCKS4a domain name, without the terminating NUL
+    pub domain: Option<&'a [u8]>,
+}
+
+/// The reply struct (server-only)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reply {
+    /// reply version (0x0)
+    pub version: u8,
+
+    /// reply code
+    pub cd: u8,
+
+    /// server bound port
+    pub dst_port: u16,
+
+    /// server bound address
+    pub dst_ip: [u8; 4],
+}
+
+impl<'a> Request<'a> {
+    /// Creates a new request to an IPv4 address
+    pub fn new(cmd: u8, dst_ip: [u8; 4], dst_port: u16, user_id: &'a str) -> Self {
+        Self {
+            version: SOCKS4_VERSION,
+            cmd,
+            dst_port,
+            dst_ip,
+            user_id: user_id.as_bytes(),
+            domain: None,
+        }
+    }
+
+    /// Creates a new SOCKS4a request to a domain name
+    pub fn with_domain(cmd: u8, domain: &'a str, dst_port: u16, user_id: &'a str) -> Self {
+        Self {
+            dst_ip: [0, 0, 0, 1],
+            domain: Some(domain.as_bytes()),
+            ..Self::new(cmd, [0; 4], dst_port, user_id)
+        }
+    }
+}
+
+impl Reply {
+    /// Creates a new reply
+    pub fn new(cd: u8, dst_ip: [u8; 4], dst_port: u16) -> Self {
+        Self {
+            version: 0x0,
+            cd,
+            dst_port,
+            dst_ip,
+        }
+    }
+}
+
+impl<'s> Sendible<'s> for Request<'s> {
+    fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut data = vec![self.version, self.cmd];
+        data.extend(self.dst_port.to_be_bytes());
+        data.extend(self.dst_ip);
+        data.extend(self.user_id);
+        data.push(0);
+
+        if let Some(domain) = self.domain {
+            data.extend(domain);
+            data.push(0);
+        }
+        Ok(data)
+    }
+
+    fn deserialize(data: &'s [u8]) -> io::Result<Self> {
+        if data.len() < 9 {
+            return Err(truncated());
+        }
+
+        let (version, cmd) = (data[0], data[1]);
+        let dst_port = u16::from_be_bytes([data[2], data[3]]);
+        let dst_ip = [data[4], data[5], data[6], data[7]];
+
+        let (user_id, rest) = until_nul(&data[8..])?;
+
+        // 0.0.0.x, x being anything but 0, announces a domain name
+        let domain = match dst_ip {
+            [0, 0, 0, x] if x != 0 => Some(until_nul(rest)?.0),
+            _ => None,
+        };
+
+        Ok(Self {
+            version,
+            cmd,
+            dst_port,
+            dst_ip,
+            user_id,
+            domain,
+        })
+    }
+}
+
+impl<'s> Sendible<'s> for Reply {
+    fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut data = vec![self.version, self.cd];
+        data.extend(self.dst_port.to_be_bytes());
+        data.extend(self.dst_ip);
+        Ok(data)
+    }
+
+    fn deserialize(data: &'s [u8]) -> io::Result<Self> {
+        if data.len() < 8 {
+            return Err(truncated());
+        }
+
+        Ok(Self {
+            version: data[0],
+            cd: data[1],
+            dst_port: u16::from_be_bytes([data[2], data[3]]),
+            dst_ip: [data[4], data[5], data[6], data[7]],
+        })
+    }
+}
+
+/// Splits `data` after its first NUL, which is left out
+fn until_nul(data: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    let end = data.iter().position(|&b| b == 0).ok_or_else(truncated)?;
+    Ok((&data[..end], &data[end + 1..]))
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated SOCKS4 message")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn request_serr_deser() {
+        let request = Request::new(command::CONNECT, [127, 0, 0, 1], 1080, "batata");
+        let serialized = request.serialize().unwrap();
+        let from_bytes = Request::deserialize(&serialized).unwrap();
+        assert_eq!(request, from_bytes);
+
+        let bytes = [4, 1, 0, 80, 142, 250, 219, 14, 0];
+        let request = Request::deserialize(&bytes).unwrap();
+        assert_eq!(request, Request::new(1, [142, 250, 219, 14], 80, ""));
+
+        assert!(Request::deserialize(&[4, 1, 0, 80, 142, 250, 219, 14, 98]).is_err());
+    }
+
+    #[test]
+    fn domain_request_serr_deser() {
+        let request = Request::with_domain(command::CONNECT, "batata", 1080, "");
+        let serialized = request.serialize().unwrap();
+        let from_bytes = Request::deserialize(&serialized).unwrap();
+        assert_eq!(request, from_bytes);
+        assert_eq!(from_bytes.domain, Some(&b"batata"[..]));
+    }
+
+    #[test]
+    fn reply_serr_deser() {
+        let reply = Reply::new(reply_code::GRANTED, [127, 0, 0, 1], 1080);
+        let serialized = reply.serialize().unwrap();
+        let from_bytes = Reply::deserialize(&serialized).unwrap();
+        assert_eq!(reply, from_bytes);
+
+        let bytes = [0, 0x5b, 0, 0, 0, 0, 0, 0];
+        let reply = Reply::deserialize(&bytes).unwrap();
+        assert_eq!(reply, Reply::new(reply_code::REJECTED, [0; 4], 0));
+    }
+}
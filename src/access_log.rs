@@ -0,0 +1,227 @@
+//! # Access log
+//! One line per finished session: when it started, who the client was,
+//! what it asked for, where it went out from, how it was answered, how
+//! much went through and why it ended.
+//!
+//! Lines are written as `key=value` text or as JSON objects, to stdout, to
+//! a file or to syslog over a Unix socket. Log files are reopened on
+//! `SIGHUP`, for logrotate to move them away.
+
+use crate::session::Session;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+/// Syslog priority of the lines: facility `daemon`, severity `info`
+#[cfg(unix)]
+const SYSLOG_PRIORITY: u8 = 3 << 3 | 6;
+
+/// How and where sessions are logged
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AccessLog {
+    /// where lines go
+    #[serde(default)]
+    pub sink: Sink,
+
+    /// how lines are written
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+/// Where access log lines go
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sink {
+    /// the standard output
+    #[default]
+    Stdout,
+
+    /// a file, appended to
+    File(PathBuf),
+
+    /// a syslog daemon listening on a Unix datagram socket, as `/dev/log`
+    #[cfg(unix)]
+    Syslog(PathBuf),
+}
+
+/// How access log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// `key=value` pairs separated by spaces
+    #[default]
+    Text,
+
+    /// a JSON object
+    Json,
+}
+
+impl AccessLog {
+    /// Logs to `sink`, as text
+    pub fn new(sink: Sink) -> Self {
+        Self {
+            sink,
+            format: LogFormat::default(),
+        }
+    }
+
+    /// Sets how lines are written
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Opens the sink
+    pub(crate) fn open(&self) -> io::Result<AccessLogger> {
+        Ok(AccessLogger {
+            format: self.format,
+            out: Mutex::new(Output::open(&self.sink)?),
+            sink: self.sink.clone(),
+        })
+    }
+}
+
+/// An open access log
+#[derive(Debug)]
+pub(crate) struct AccessLogger {
+    format: LogFormat,
+    sink: Sink,
+    out: Mutex<Output>,
+}
+
+#[derive(Debug)]
+enum Output {
+    Stdout,
+    File(File),
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+}
+
+impl Output {
+    fn open(sink: &Sink) -> io::Result<Self> {
+        match sink {
+            Sink::Stdout => Ok(Self::Stdout),
+            Sink::File(path) => Ok(Self::File(append(path)?)),
+            #[cfg(unix)]
+            Sink::Syslog(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Ok(Self::Syslog(socket))
+            }
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Self::Stdout => writeln!(io::stdout().lock(), "{line}"),
+            Self::File(file) => file.write_all(format!("{line}\n").as_bytes()),
+            #[cfg(unix)]
+            Self::Syslog(socket) => {
+                let message = format!("<{SYSLOG_PRIORITY}>proksi[{}]: {line}", std::process::id());
+                socket.send(message.as_bytes()).map(|_| ())
+            }
+        }
+    }
+}
+
+impl AccessLogger {
+    /// Tells if the log goes to a file, that would need reopening
+    pub fn is_file(&self) -> bool {
+        matches!(self.sink, Sink::File(_))
+    }
+
+    /// Reopens the sink, as a log file that has been moved away
+    pub fn reopen(&self) -> io::Result<()> {
+        let out = Output::open(&self.sink)?;
+        *self.out.lock().unwrap() = out;
+        Ok(())
+    }
+
+    /// Logs a finished session, `close` telling why it ended
+    pub fn log(&self, session: &Session, close: &str) -> io::Result<()> {
+        let line = match self.format {
+            LogFormat::Text => text(session, close),
+            LogFormat::Json => json(session, close),
+        };
+        self.out.lock().unwrap().write_line(&line)
+    }
+}
+
+fn text(session: &Session, close: &str) -> String {
+    let state = session.state();
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+
+    format!(
+        "{} id={} client={} user={} command={} destination={} resolved={} local={} reply={} up={} down={} duration={:.3}s close={close:?}",
+        timestamp(session.started),
+        session.id,
+        or_dash(state.peer.map(|peer| peer.to_string())),
+        or_dash(state.user),
+        state.command.unwrap_or("-"),
+        or_dash(state.destination),
+        or_dash(state.resolved.map(|addr| addr.to_string())),
+        or_dash(state.local.map(|addr| addr.to_string())),
+        or_dash(state.reply.map(|rep| rep.to_string())),
+        session.traffic.up(),
+        session.traffic.down(),
+        session.elapsed().as_secs_f64(),
+    )
+}
+
+fn json(session: &Session, close: &str) -> String {
+    let state = session.state();
+
+    json!({
+        "timestamp": timestamp(session.started),
+        "id": session.id,
+        "client": state.peer.map(|peer| peer.to_string()),
+        "user": state.user,
+        "command": state.command,
+        "destination": state.destination,
+        "resolved": state.resolved.map(|addr| addr.to_string()),
+        "local": state.local.map(|addr| addr.to_string()),
+        "reply": state.reply,
+        "up": session.traffic.up(),
+        "down": session.traffic.down(),
+        "duration": session.elapsed().as_secs_f64(),
+        "close": close,
+    })
+    .to_string()
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Writes `time` in RFC 3339, in UTC and to the millisecond
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // days to a civil date, from Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
@@ -0,0 +1,474 @@
+//! # Admin
+//! An HTTP API controlling a running server, for a control plane to drive
+//! it. Requests and responses are JSON.
+//!
+//! The API is served on a loopback address or on a Unix socket, and every
+//! request carries the token as `Authorization: Bearer <token>`.
+//!
+//! | Request                          | Does                                       |
+//! |----------------------------------|--------------------------------------------|
+//! | `GET /sessions`                  | lists the running sessions                 |
+//! | `DELETE /sessions/{id}`          | closes a session                           |
+//! | `GET /users`                     | lists the users, without their passwords   |
+//! | `POST /users`                    | adds a user                                |
+//! | `DELETE /users/{name}`           | removes a user                             |
+//! | `POST /users/{name}/disable`     | disables a user, `/enable` enabling it     |
+//! | `DELETE /users/{name}/sessions`  | closes the sessions of a user              |
+//! | `GET`, `PUT /routes`             | shows or replaces the routing rules        |
+//! | `GET /acls`                      | shows the rules of every listener          |
+//! | `GET`, `PUT /acls/{index}`       | shows or replaces the rules of a listener  |
+//! | `POST /reload`                   | reloads the configuration file             |
+//! | `GET`, `PUT /drain`              | shows or sets drain mode                   |
+//! | `GET /config`                    | shows the effective configuration          |
+//!
+//! Removing or disabling a user leaves their running sessions alone. A
+//! reload applies the users, host overrides, routing rules and listener
+//! rules of the file, listeners being matched by position; other settings
+//! take a restart.
+
+use crate::connection::Stream;
+use crate::handle::ServerHandle;
+use crate::route::Rule;
+use crate::session::SessionInfo;
+#[cfg(unix)]
+use crate::unix::UnixListen;
+use crate::user::User;
+use crate::{access_log, http, Server};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time;
+use tracing::{info, warn};
+
+/// Longest request body read
+const MAX_BODY: usize = 1 << 20;
+
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Permissions of the Unix socket unless set
+#[cfg(unix)]
+const SOCKET_MODE: u32 = 0o600;
+
+/// Where and how the admin API is served, serialized without its token
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Admin {
+    /// where the API listens
+    pub listen: AdminListen,
+
+    /// token clients authenticate with
+    #[serde(skip_serializing)]
+    pub token: String,
+
+    /// configuration file read again on reload
+    #[serde(default)]
+    pub config: Option<PathBuf>,
+}
+
+/// Where the admin API listens
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminListen {
+    /// a loopback address
+    Tcp(SocketAddr),
+
+    /// a Unix socket, only readable by its owner unless told otherwise
+    #[cfg(unix)]
+    Unix(UnixListen),
+}
+
+/// A bound admin API listener
+pub(crate) enum AdminListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// An API error, answered with its status and message
+struct Error(u16, String);
+
+type Response = Result<Value, Error>;
+
+impl Admin {
+    /// Serves the API at `listen`, for clients with `token`
+    pub fn new(listen: AdminListen, token: &str) -> Self {
+        Self {
+            listen,
+            token: token.to_string(),
+            config: None,
+        }
+    }
+
+    /// Sets the configuration file read again on reload
+    pub fn with_config(mut self, config: impl Into<PathBuf>) -> Self {
+        self.config = Some(config.into());
+        self
+    }
+
+    /// Returns the TCP address of the API, if it listens on one
+    pub(crate) fn addr(&self) -> Option<SocketAddr> {
+        match self.listen {
+            AdminListen::Tcp(addr) => Some(addr),
+            #[cfg(unix)]
+            AdminListen::Unix(_) => None,
+        }
+    }
+
+    /// Binds the listener, refusing addresses reachable from other hosts
+    /// and empty tokens. A TCP address with port 0 is updated with the
+    /// port picked.
+    pub(crate) fn bind(&mut self) -> io::Result<AdminListener> {
+        if self.token.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The admin API needs a token",
+            ));
+        }
+
+        match self.listen {
+            AdminListen::Tcp(ref mut addr) => {
+                if !addr.ip().is_loopback() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("The admin API only listens on loopback, not {addr}"),
+                    ));
+                }
+                let listener = std::net::TcpListener::bind(*addr)?;
+                listener.set_nonblocking(true)?;
+                *addr = listener.local_addr()?;
+                Ok(AdminListener::Tcp(TcpListener::from_std(listener)?))
+            }
+            #[cfg(unix)]
+            AdminListen::Unix(ref unix) => {
+                let unix = UnixListen {
+                    mode: unix.mode.or(Some(SOCKET_MODE)),
+                    ..unix.clone()
+                };
+                Ok(AdminListener::Unix(unix.bind()?))
+            }
+        }
+    }
+}
+
+/// Answers API requests on `listener` until the server stops
+pub(crate) async fn serve(listener: AdminListener, server: Arc<Server>, handle: ServerHandle) {
+    loop {
+        let accepted = tokio::select! {
+            res = accept(&listener, &server, &handle) => res,
+            _ = handle.stopped() => return,
+        };
+
+        if let Err(err) = accepted {
+            warn!(error = %err, "Failed to accept an admin request");
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// Accepts a client and answers it in the background
+async fn accept(
+    listener: &AdminListener,
+    server: &Arc<Server>,
+    handle: &ServerHandle,
+) -> io::Result<()> {
+    match listener {
+        AdminListener::Tcp(listener) => {
+            let (stream, _) = listener.accept().await?;
+            spawn(stream, server, handle);
+        }
+        #[cfg(unix)]
+        AdminListener::Unix(listener) => {
+            let (stream, _) = listener.accept().await?;
+            spawn(stream, server, handle);
+        }
+    }
+    Ok(())
+}
+
+fn spawn<S: Stream + 'static>(mut stream: S, server: &Arc<Server>, handle: &ServerHandle) {
+    let server = Arc::clone(server);
+    let api = handle.clone();
+    handle.spawn(async move {
+        if let Err(err) = respond(&mut stream, &server, &api).await {
+            warn!(error = %err, "Failed to answer an admin request");
+        }
+    });
+}
+
+async fn respond<S: Stream>(
+    stream: &mut S,
+    server: &Server,
+    handle: &ServerHandle,
+) -> io::Result<()> {
+    let request = time::timeout(REQUEST_TIMEOUT, read_request(stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?;
+
+    let result = match request {
+        Ok((head, _)) if !authorized(&head, server) => {
+            Err(Error(401, "Missing or wrong token".to_string()))
+        }
+        Ok((head, body)) => {
+            let result = route(server, handle, &head.method, &head.target, &body);
+            if head.method != "GET" && result.is_ok() {
+                info!(method = %head.method, target = %head.target, "Admin request served");
+            }
+            result
+        }
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => Err(Error(400, err.to_string())),
+        Err(err) => return Err(err),
+    };
+
+    let (status, body) = match result {
+        Ok(body) => (200, body),
+        Err(Error(status, msg)) => (status, json!({ "error": msg })),
+    };
+    let response =
+        http::response_with_body(status, "application/json", body.to_string().as_bytes());
+    stream.write_all(&response).await
+}
+
+/// Reads a request head and the body that follows, as long as its
+/// `Content-Length` tells
+async fn read_request<S: Stream>(stream: &mut S) -> io::Result<(http::RequestHead, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let len = http::read_head(stream, &mut buf).await?;
+    let head = http::RequestHead::parse(&buf[..len])?;
+
+    let length = match head.header("content-length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| invalid(format!("Invalid Content-Length {length:?}")))?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Err(invalid(format!("Request body over {MAX_BODY} bytes")));
+    }
+
+    let mut body = buf.split_off(len);
+    while body.len() < length {
+        if stream.read_buf(&mut body).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    body.truncate(length);
+
+    Ok((head, body))
+}
+
+/// Tells if the request carries the token, comparing it in constant time
+fn authorized(head: &http::RequestHead, server: &Server) -> bool {
+    let Some(admin) = server.admin.as_ref() else {
+        return false;
+    };
+    let Some(token) = head
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    token.len() == admin.token.len()
+        && token
+            .bytes()
+            .zip(admin.token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn route(
+    server: &Server,
+    handle: &ServerHandle,
+    method: &str,
+    target: &str,
+    body: &[u8],
+) -> Response {
+    let path = target.split('?').next().unwrap_or_default();
+    let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+
+    match (segments.as_slice(), method) {
+        (["sessions"], "GET") => Ok(handle.sessions().iter().map(session).collect()),
+        (["sessions", id], "DELETE") => {
+            let id = id
+                .parse()
+                .map_err(|_| Error(404, format!("No session {id}")))?;
+            if !handle.kill_session(id) {
+                return Err(Error(404, format!("No session {id}")));
+            }
+            Ok(json!({ "killed": 1 }))
+        }
+
+        (["users"], "GET") => Ok(json!(server.allowed_users)),
+        (["users"], "POST") => {
+            let user: User = parse(body)?;
+            if server
+                .allowed_users
+                .list()
+                .iter()
+                .any(|u| u.username == user.username)
+            {
+                return Err(Error(409, format!("User {} already exists", user.username)));
+            }
+            let added = json!(user);
+            server.allowed_users.insert(user);
+            Ok(added)
+        }
+        (["users", name], "DELETE") => {
+            let name = decode(name)?;
+            match server.allowed_users.remove(&name) {
+                Some(user) => Ok(json!(user)),
+                None => Err(no_user(&name)),
+            }
+        }
+        (["users", name, toggle @ ("disable" | "enable")], "POST") => {
+            let name = decode(name)?;
+            let disabled = *toggle == "disable";
+            if !server.allowed_users.set_disabled(&name, disabled) {
+                return Err(no_user(&name));
+            }
+            Ok(json!({ "username": name, "disabled": disabled }))
+        }
+        (["users", name, "sessions"], "DELETE") => {
+            let killed = handle.kill_user_sessions(&decode(name)?);
+            Ok(json!({ "killed": killed }))
+        }
+
+        (["routes"], "GET") => Ok(json!(server.routes)),
+        (["routes"], "PUT") => {
+            let rules: Vec<Rule> = parse(body)?;
+            server.routes.replace(rules);
+            Ok(json!(server.routes))
+        }
+
+        (["acls"], "GET") => Ok(server
+            .addr
+            .iter()
+            .map(|listener| json!({ "addr": listener.addr, "rules": listener.rules }))
+            .collect()),
+        (["acls", index], "GET" | "PUT") => {
+            let listener = index
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| server.addr.get(index))
+                .ok_or_else(|| Error(404, format!("No listener {index}")))?;
+            if method == "PUT" {
+                let rules: Vec<Rule> = parse(body)?;
+                listener.rules.replace(rules);
+            }
+            Ok(json!(listener.rules))
+        }
+
+        (["reload"], "POST") => {
+            let path = server
+                .admin
+                .as_ref()
+                .and_then(|admin| admin.config.as_deref())
+                .ok_or_else(|| Error(409, "No configuration file to reload".to_string()))?;
+            reload(server, path).map_err(|err| match err.kind() {
+                io::ErrorKind::InvalidData => Error(400, err.to_string()),
+                _ => Error(500, format!("{}: {err}", path.display())),
+            })?;
+            Ok(json!(server))
+        }
+
+        (["drain"], "GET") => Ok(json!({ "draining": handle.is_draining() })),
+        (["drain"], "PUT") => {
+            #[derive(Deserialize)]
+            struct Drain {
+                draining: bool,
+            }
+
+            let drain: Drain = parse(body)?;
+            handle.set_draining(drain.draining);
+            Ok(json!({ "draining": drain.draining }))
+        }
+
+        (["config"], "GET") => Ok(json!(server)),
+
+        (
+            ["sessions"]
+            | ["sessions", _]
+            | ["users"]
+            | ["users", _]
+            | ["users", _, _]
+            | ["routes"]
+            | ["acls"]
+            | ["acls", _]
+            | ["reload"]
+            | ["drain"]
+            | ["config"],
+            _,
+        ) => Err(Error(405, format!("No {method} {path}"))),
+        _ => Err(Error(404, format!("No {path}"))),
+    }
+}
+
+/// Applies the reloadable settings of the configuration file at `path`
+fn reload(server: &Server, path: &Path) -> io::Result<()> {
+    let config: Server = serde_json::from_str(&std::fs::read_to_string(path)?)
+        .map_err(|err| invalid(format!("{}: {err}", path.display())))?;
+
+    server.allowed_users.replace(config.allowed_users.list());
+    server.hosts.replace(config.hosts.entries());
+    server.routes.replace(config.routes.rules());
+    for (listener, reloaded) in server.addr.iter().zip(&config.addr) {
+        listener.rules.replace(reloaded.rules.rules());
+    }
+    Ok(())
+}
+
+fn session(session: &SessionInfo) -> Value {
+    json!({
+        "id": session.id,
+        "client": session.peer,
+        "user": session.user,
+        "command": session.command,
+        "destination": session.destination,
+        "started": access_log::timestamp(session.started),
+        "up": session.up,
+        "down": session.down,
+        "phase": session.phase,
+    })
+}
+
+fn parse<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, Error> {
+    serde_json::from_slice(body).map_err(|err| Error(400, err.to_string()))
+}
+
+fn no_user(name: &str) -> Error {
+    Error(404, format!("No user {name}"))
+}
+
+/// Decodes the percent-escapes of a path segment
+fn decode(segment: &str) -> Result<String, Error> {
+    let invalid = || Error(400, format!("Invalid path segment {segment:?}"));
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail
+                .get(..2)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,148 @@
+//! # Cidr
+//! IPv4 and IPv6 address blocks, written as `10.0.0.0/8` or `2001:db8::/32`.
+//! A bare address is a block holding only itself.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Creates a block, failing if the prefix is longer than the address
+    pub fn new(addr: IpAddr, prefix: u8) -> io::Result<Self> {
+        if prefix > max_prefix(addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid prefix length {prefix} for {addr}"),
+            ));
+        }
+
+        Ok(Self { addr, prefix })
+    }
+
+    /// The first address of the block
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// The prefix length
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// The `n`-th address of the block, wrapping around its size
+    pub fn nth(&self, n: u128) -> IpAddr {
+        let bits = max_prefix(self.addr);
+        let host = u128::MAX
+            .checked_shr((128 - bits + self.prefix) as u32)
+            .unwrap_or(0);
+
+        match self.addr {
+            IpAddr::V4(net) => {
+                let net = u32::from(net) & !(host as u32);
+                IpAddr::V4((net | (n & host) as u32).into())
+            }
+            IpAddr::V6(net) => IpAddr::V6((u128::from(net) & !host | (n & host)).into()),
+        }
+    }
+
+    /// The `n`-th host address of the block, wrapping around the hosts.
+    /// IPv4 blocks of four addresses or more leave out their network and
+    /// broadcast addresses.
+    pub fn host(&self, n: u128) -> IpAddr {
+        match self.addr {
+            IpAddr::V4(_) if self.prefix <= 30 => {
+                let hosts = (1u128 << (32 - self.prefix)) - 2;
+                self.nth(1 + n % hosts)
+            }
+            _ => self.nth(n),
+        }
+    }
+
+    /// Tells if `ip` is part of the block. IPv4-mapped IPv6 addresses
+    /// are treated as their IPv4 counterpart.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => mask(
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn mask(net: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    let shift = (bits - prefix) as u32;
+    net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    fn from(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            prefix: max_prefix(addr),
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid CIDR {s}"));
+
+        match s.split_once('/') {
+            Some((addr, prefix)) => Self::new(
+                addr.parse().map_err(|_| invalid())?,
+                prefix.parse().map_err(|_| invalid())?,
+            ),
+            None => s.parse::<IpAddr>().map(Self::from).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
@@ -0,0 +1,108 @@
+//! # Connection
+//! What sessions run over: any byte stream, along with the addresses of
+//! both of its ends when the transport has such a thing.
+
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// A transport a SOCKS session can run over
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Addresses of a client connection
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConnectionInfo {
+    /// client address, matched by routing rules
+    pub peer: Option<SocketAddr>,
+
+    /// address the client connected to, where BIND listens by default
+    pub local: Option<SocketAddr>,
+
+    /// credentials of the client process, over Unix sockets
+    pub cred: Option<PeerCred>,
+
+    /// verified client certificate, over TLS
+    pub cert: Option<PeerCert>,
+}
+
+/// Credentials of the process on the other end of a Unix socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    /// user id
+    pub uid: u32,
+
+    /// group id
+    pub gid: u32,
+
+    /// process id, when the platform tells
+    pub pid: Option<i32>,
+}
+
+/// A verified TLS client certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCert {
+    /// subject, as in `CN=alice, O=Example`
+    pub subject: String,
+
+    /// common names, then DNS names and email addresses of the subject
+    /// alternative name
+    pub names: Vec<String>,
+}
+
+impl PeerCert {
+    /// Tells if the subject is `subject`, ignoring the spaces around commas
+    pub fn has_subject(&self, subject: &str) -> bool {
+        self.subject
+            .split(',')
+            .map(str::trim)
+            .eq(subject.split(',').map(str::trim))
+    }
+}
+
+impl ConnectionInfo {
+    /// Creates the metadata of a connection
+    pub fn new(peer: Option<SocketAddr>, local: Option<SocketAddr>) -> Self {
+        Self {
+            peer,
+            local,
+            cred: None,
+            cert: None,
+        }
+    }
+
+    /// Sets the credentials of the client process
+    pub fn with_cred(mut self, cred: PeerCred) -> Self {
+        self.cred = Some(cred);
+        self
+    }
+
+    /// Sets the verified client certificate
+    pub fn with_cert(mut self, cert: PeerCert) -> Self {
+        self.cert = Some(cert);
+        self
+    }
+
+    /// Reads the addresses of a TCP connection
+    pub fn tcp(stream: &TcpStream) -> io::Result<Self> {
+        Ok(Self::new(
+            Some(stream.peer_addr()?),
+            Some(stream.local_addr()?),
+        ))
+    }
+
+    /// Reads the peer credentials of a Unix socket connection, which has
+    /// no IP addresses
+    #[cfg(unix)]
+    pub fn unix(stream: &tokio::net::UnixStream) -> io::Result<Self> {
+        let cred = stream.peer_cred()?;
+
+        Ok(Self::default().with_cred(PeerCred {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        }))
+    }
+}
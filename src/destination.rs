@@ -0,0 +1,132 @@
+//! # Destination
+//! Where a request wants to go, before any resolution takes place.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use socks_rs::request::{addr_type, Request};
+use socks_rs::socks4;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+/// A destination address, either already known or still to be resolved
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Destination {
+    /// an IPv4 or IPv6 socket address
+    Addr(SocketAddr),
+
+    /// a domain name and port
+    Domain(String, u16),
+}
+
+impl Destination {
+    /// Reads the destination out of a SOCKS request
+    pub fn from_request(request: &Request) -> io::Result<Self> {
+        let (dst_ip, dst_port) = (request.dst_addr, request.dst_port);
+
+        let dst = match request.atyp {
+            addr_type::IP_V4 => Self::Addr(SocketAddr::from((
+                TryInto::<[u8; 4]>::try_into(dst_ip).unwrap(),
+                dst_port,
+            ))),
+            addr_type::DOMAIN_NAME => {
+                let host = std::str::from_utf8(dst_ip)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                Self::Domain(host.trim().to_string(), dst_port)
+            }
+            addr_type::IP_V6 => Self::Addr(SocketAddr::from((
+                TryInto::<[u8; 16]>::try_into(dst_ip).unwrap(),
+                dst_port,
+            ))),
+            atyp => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("ADDRESS TYPE NOT SUPPORTED ({atyp})"),
+                ))
+            }
+        };
+
+        Ok(dst)
+    }
+
+    /// Reads the destination out of a SOCKS4 request, or of a SOCKS4a one
+    /// naming a domain
+    pub fn from_socks4(request: &socks4::Request) -> io::Result<Self> {
+        match request.domain {
+            Some(domain) => {
+                let host = std::str::from_utf8(domain)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                Ok(Self::Domain(host.trim().to_string(), request.dst_port))
+            }
+            None => Ok(Self::Addr(SocketAddr::from((
+                request.dst_ip,
+                request.dst_port,
+            )))),
+        }
+    }
+
+    /// The destination port
+    pub fn port(&self) -> u16 {
+        match self {
+            Self::Addr(addr) => addr.port(),
+            Self::Domain(_, port) => *port,
+        }
+    }
+
+    /// The SOCKS address type and raw address bytes of the destination
+    pub fn to_socks(&self) -> (u8, Vec<u8>) {
+        match self {
+            Self::Addr(SocketAddr::V4(addr)) => (addr_type::IP_V4, addr.ip().octets().to_vec()),
+            Self::Addr(SocketAddr::V6(addr)) => (addr_type::IP_V6, addr.ip().octets().to_vec()),
+            Self::Domain(host, _) => (addr_type::DOMAIN_NAME, host.as_bytes().to_vec()),
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Addr(addr) => write!(f, "{addr}"),
+            Self::Domain(host, port) => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+impl From<SocketAddr> for Destination {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Addr(addr)
+    }
+}
+
+impl FromStr for Destination {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(Self::Addr(addr));
+        }
+
+        s.rsplit_once(':')
+            .and_then(|(host, port)| Some(Self::Domain(host.to_string(), port.parse().ok()?)))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid destination {s}"),
+                )
+            })
+    }
+}
+
+impl<'de> Deserialize<'de> for Destination {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Destination {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
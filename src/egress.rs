@@ -0,0 +1,247 @@
+//! # Egress
+//! Where outbound connections leave the host from: a local source address
+//! and/or a network interface (`SO_BINDTODEVICE`, Linux only), and the
+//! firewall mark (`SO_MARK`, Linux only) policy routing can match them by.
+//!
+//! Instead of a single address, the source can be picked per connection out
+//! of a pool of addresses or out of a whole prefix. Addresses taken from a
+//! prefix must be routable locally, e.g. `ip -6 route add local <prefix> dev lo`.
+
+use crate::cidr::Cidr;
+use crate::destination::Destination;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::{TcpSocket, TcpStream};
+
+/// How a source address is picked out of a pool or prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// one after the other
+    #[default]
+    RoundRobin,
+
+    /// at random for each connection
+    Random,
+
+    /// always the same one for a given user
+    StickyUser,
+
+    /// always the same one for a given destination
+    StickyDestination,
+}
+
+/// Outbound socket settings, every field left unset keeping the kernel default
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Egress {
+    /// local source address
+    #[serde(default)]
+    pub bind: Option<IpAddr>,
+
+    /// network interface name
+    #[serde(default)]
+    pub interface: Option<String>,
+
+    /// source addresses to pick from, taking precedence over `bind`
+    #[serde(default)]
+    pub pool: Vec<IpAddr>,
+
+    /// prefix to pick source addresses from, when `pool` is empty. IPv4
+    /// network and broadcast addresses are never picked.
+    #[serde(default)]
+    pub prefix: Option<Cidr>,
+
+    /// how addresses are picked out of `pool` or `prefix`
+    #[serde(default)]
+    pub strategy: Strategy,
+
+    /// firewall mark
+    #[serde(default)]
+    pub mark: Option<u32>,
+
+    #[serde(skip)]
+    next: Arc<AtomicUsize>,
+}
+
+/// What sticky strategies pick addresses by
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Affinity<'a> {
+    pub user: Option<&'a str>,
+    pub dst: &'a Destination,
+}
+
+impl Egress {
+    /// Creates an egress with a single source address
+    pub fn bind(ip: IpAddr) -> Self {
+        Self {
+            bind: Some(ip),
+            ..Self::default()
+        }
+    }
+
+    /// Creates an egress picking source addresses out of `pool`
+    pub fn pool(pool: Vec<IpAddr>, strategy: Strategy) -> Self {
+        Self {
+            pool,
+            strategy,
+            ..Self::default()
+        }
+    }
+
+    /// Creates an egress picking source addresses out of `prefix`
+    pub fn prefix(prefix: Cidr, strategy: Strategy) -> Self {
+        Self {
+            prefix: Some(prefix),
+            strategy,
+            ..Self::default()
+        }
+    }
+
+    /// Merges two settings, the ones set on `self` winning over `fallback`
+    pub fn or(&self, fallback: &Egress) -> Egress {
+        let pooled = match self.pool.is_empty() && self.prefix.is_none() {
+            true => fallback,
+            false => self,
+        };
+
+        Egress {
+            bind: self.bind.or(fallback.bind),
+            interface: self
+                .interface
+                .clone()
+                .or_else(|| fallback.interface.clone()),
+            pool: pooled.pool.clone(),
+            prefix: pooled.prefix,
+            strategy: pooled.strategy,
+            next: Arc::clone(&pooled.next),
+            mark: self.mark.or(fallback.mark),
+        }
+    }
+
+    /// Picks the source address for a connection to `target`, out of the
+    /// pool or prefix if there is one of the same family, `bind` otherwise
+    pub(crate) fn source(&self, target: IpAddr, affinity: Affinity) -> Option<IpAddr> {
+        let same_family = |ip: &IpAddr| ip.is_ipv4() == target.is_ipv4();
+
+        let pool: Vec<_> = self.pool.iter().copied().filter(same_family).collect();
+        if !pool.is_empty() {
+            return Some(pool[self.pick(affinity) as usize % pool.len()]);
+        }
+
+        match self.prefix {
+            Some(prefix) if same_family(&prefix.addr()) => {
+                Some(prefix.host(self.pick(affinity) as u128))
+            }
+            _ => self.bind.filter(same_family),
+        }
+    }
+
+    fn pick(&self, affinity: Affinity) -> u64 {
+        let mut hasher = DefaultHasher::new();
+
+        match self.strategy {
+            Strategy::RoundRobin => return self.next.fetch_add(1, Ordering::Relaxed) as u64,
+            Strategy::Random => return RandomState::new().hash_one(()),
+            Strategy::StickyUser => affinity.user.hash(&mut hasher),
+            Strategy::StickyDestination => affinity.dst.hash(&mut hasher),
+        }
+
+        hasher.finish()
+    }
+
+    /// Connects to `addr` from this egress
+    pub(crate) async fn connect(
+        &self,
+        addr: SocketAddr,
+        affinity: Affinity<'_>,
+    ) -> io::Result<TcpStream> {
+        let source = self.source(addr.ip(), affinity);
+
+        if source.is_none() && self.interface.is_none() && self.mark.is_none() {
+            return TcpStream::connect(addr).await;
+        }
+
+        let socket = self.socket(addr.ip())?;
+        if let Some(ip) = source {
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+
+        socket.connect(addr).await
+    }
+
+    /// Listens on `port` of the egress address, or of `default` if there is
+    /// none, so that BIND connections come in the same way others go out
+    #[cfg(feature = "bind")]
+    pub(crate) fn listen(
+        &self,
+        default: IpAddr,
+        port: u16,
+        affinity: Affinity,
+    ) -> io::Result<tokio::net::TcpListener> {
+        let ip = self.source(default, affinity).unwrap_or(default);
+
+        let socket = self.socket(ip)?;
+        socket.set_reuseaddr(true)?;
+        socket.bind(SocketAddr::new(ip, port))?;
+
+        socket.listen(1024)
+    }
+
+    fn socket(&self, ip: IpAddr) -> io::Result<TcpSocket> {
+        let socket = match ip {
+            IpAddr::V4(_) => TcpSocket::new_v4()?,
+            IpAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+
+        if let Some(ref interface) = self.interface {
+            bind_device(&socket, interface)?;
+        }
+        if let Some(mark) = self.mark {
+            set_mark(&socket, mark)?;
+        }
+
+        Ok(socket)
+    }
+}
+
+impl PartialEq for Egress {
+    fn eq(&self, other: &Self) -> bool {
+        self.bind == other.bind
+            && self.interface == other.interface
+            && self.pool == other.pool
+            && self.prefix == other.prefix
+            && self.strategy == other.strategy
+            && self.mark == other.mark
+    }
+}
+
+impl Eq for Egress {}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    Err(io::Error::other(format!(
+        "Binding to interface {interface} is not supported on this platform"
+    )))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn set_mark(socket: &TcpSocket, mark: u32) -> io::Result<()> {
+    socket2::SockRef::from(socket).set_mark(mark)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn set_mark(_socket: &TcpSocket, mark: u32) -> io::Result<()> {
+    Err(io::Error::other(format!(
+        "Setting firewall mark {mark} is not supported on this platform"
+    )))
+}
@@ -0,0 +1,150 @@
+//! # Handle
+//! Controlling a server running in the background: where it listens, what
+//! sessions it runs, closing some of them, draining it, and shutting it down
+//! either gracefully or right away.
+
+use crate::session::{Registry, SessionInfo};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Handle to a running server, as returned by [`Server::spawn`](crate::Server::spawn).
+///
+/// Cloning a `ServerHandle` gives another handle to the same server. Dropping
+/// every handle leaves the server running.
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+    sessions: Registry,
+    draining: Arc<AtomicBool>,
+    stop: CancellationToken,
+    kill: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(local_addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            local_addrs,
+            metrics_addr: None,
+            admin_addr: None,
+            sessions: Registry::default(),
+            draining: Arc::default(),
+            stop: CancellationToken::new(),
+            kill: CancellationToken::new(),
+            tracker: TaskTracker::new(),
+        }
+    }
+
+    pub(crate) fn with_metrics_addr(mut self, metrics_addr: Option<SocketAddr>) -> Self {
+        self.metrics_addr = metrics_addr;
+        self
+    }
+
+    pub(crate) fn with_admin_addr(mut self, admin_addr: Option<SocketAddr>) -> Self {
+        self.admin_addr = admin_addr;
+        self
+    }
+
+    pub(crate) fn with_sessions(mut self, sessions: Registry) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Returns the address the first listener listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// Returns the addresses every listener listens on, in order
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Returns the address metrics are served on, if they are
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// Returns the TCP address the admin API is served on, if it is
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    /// Returns the sessions running right now, oldest first
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.sessions.list()
+    }
+
+    /// Closes the session with this id, telling if it was still running
+    pub fn kill_session(&self, id: u64) -> bool {
+        self.sessions.kill(id)
+    }
+
+    /// Closes every session of `user`, returning how many were running
+    pub fn kill_user_sessions(&self, user: &str) -> usize {
+        self.sessions.kill_user(user)
+    }
+
+    /// Drains the server, or stops draining it: while draining, new
+    /// connections are closed right away and running sessions go on
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    /// Tells if the server is draining
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Stops accepting connections and waits for the open sessions to end,
+    /// closing the ones still open after `deadline`
+    pub async fn shutdown(&self, deadline: Duration) {
+        self.stop.cancel();
+        self.tracker.close();
+
+        if time::timeout(deadline, self.tracker.wait()).await.is_err() {
+            self.shutdown_now();
+            self.tracker.wait().await;
+        }
+    }
+
+    /// Stops accepting connections and closes every open session
+    pub fn shutdown_now(&self) {
+        self.stop.cancel();
+        self.kill.cancel();
+        self.tracker.close();
+    }
+
+    /// Resolves once the server is shut down and every session has ended
+    pub async fn closed(&self) {
+        self.tracker.wait().await
+    }
+
+    /// Resolves once the server stops accepting connections
+    pub(crate) async fn stopped(&self) {
+        self.stop.cancelled().await
+    }
+
+    /// Runs `task` in the background until it is done or the server is
+    /// shut down right away
+    pub(crate) fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let kill = self.kill.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                _ = task => {}
+                _ = kill.cancelled() => {}
+            }
+        });
+    }
+}
@@ -0,0 +1,143 @@
+//! # Hosts
+//! Static host overrides applied to `DOMAIN_NAME` requests before any
+//! resolution takes place, much like an `/etc/hosts` private to the server.
+//!
+//! Patterns are either exact names (`db.internal`) or wildcards (`*.corp`)
+//! matching any subdomain. An override points to an address or to another
+//! name, which is looked up again so rewrites can be chained.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::{fs, io};
+
+/// Maximum number of name-to-name rewrites followed for a single lookup
+const MAX_REWRITES: usize = 8;
+
+/// What a host override points to
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Target {
+    /// a fixed address, no resolution needed
+    Addr(IpAddr),
+
+    /// another name to be resolved instead
+    Name(String),
+}
+
+/// Shared, reloadable table of host overrides.
+///
+/// Cloning a `Hosts` gives another handle to the same table, so a handle
+/// taken before the server starts can be used to update it at runtime.
+#[derive(Debug, Clone, Default)]
+pub struct Hosts {
+    inner: Arc<RwLock<HashMap<String, Target>>>,
+}
+
+impl Hosts {
+    /// Creates an empty table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads a table from a JSON file mapping patterns to targets
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let hosts = Self::new();
+        hosts.reload(path)?;
+        Ok(hosts)
+    }
+
+    /// Replaces the whole table with the contents of a JSON file
+    pub fn reload<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        let map: HashMap<String, Target> = serde_json::from_str(&contents)?;
+        self.replace(map);
+        Ok(())
+    }
+
+    /// Replaces the whole table
+    pub fn replace(&self, map: HashMap<String, Target>) {
+        let map = map
+            .into_iter()
+            .map(|(pattern, target)| (normalize(&pattern), target))
+            .collect();
+        *self.inner.write().unwrap() = map;
+    }
+
+    /// Returns a copy of the whole table
+    pub fn entries(&self) -> HashMap<String, Target> {
+        self.inner.read().unwrap().clone()
+    }
+
+    /// Adds or updates a single override
+    pub fn insert(&self, pattern: &str, target: Target) {
+        self.inner
+            .write()
+            .unwrap()
+            .insert(normalize(pattern), target);
+    }
+
+    /// Removes a single override
+    pub fn remove(&self, pattern: &str) -> Option<Target> {
+        self.inner.write().unwrap().remove(&normalize(pattern))
+    }
+
+    /// Returns the override for `host`, exact matches winning over wildcards
+    /// and longer wildcards over shorter ones.
+    pub fn get(&self, host: &str) -> Option<Target> {
+        let map = self.inner.read().unwrap();
+        let host = normalize(host);
+
+        if let Some(target) = map.get(&host) {
+            return Some(target.clone());
+        }
+
+        host.match_indices('.')
+            .find_map(|(i, _)| map.get(&format!("*{}", &host[i..])))
+            .cloned()
+    }
+
+    /// Applies the overrides to `host`, following name rewrites.
+    /// Names without an override are returned unchanged.
+    pub fn rewrite(&self, host: &str) -> Target {
+        let mut target = Target::Name(host.to_string());
+
+        for _ in 0..MAX_REWRITES {
+            match target {
+                Target::Name(ref name) => match self.get(name) {
+                    Some(next) => target = next,
+                    None => break,
+                },
+                Target::Addr(_) => break,
+            }
+        }
+
+        target
+    }
+}
+
+impl From<HashMap<String, Target>> for Hosts {
+    fn from(map: HashMap<String, Target>) -> Self {
+        let hosts = Self::new();
+        hosts.replace(map);
+        hosts
+    }
+}
+
+impl<'de> Deserialize<'de> for Hosts {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashMap::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl Serialize for Hosts {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.inner.read().unwrap().serialize(serializer)
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,240 @@
+//! # HTTP
+//! The HTTP/1.1 proxy front-end, for clients that know no SOCKS: `CONNECT`
+//! tunnels, and plain HTTP requests to absolute URIs when forwarding is on.
+//!
+//! A forwarded request is sent with `Connection: close`, so that a client
+//! connection only ever carries one of them.
+
+use crate::connection::Stream;
+use crate::destination::Destination;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use socks_rs::reply::reply_opt;
+use std::io;
+use tokio::io::AsyncReadExt;
+
+/// Longest request head read before giving up
+const MAX_HEAD: usize = 8192;
+
+/// Headers only meant for the proxy, left out of forwarded requests
+const HOP_BY_HOP: [&str; 4] = [
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+];
+
+/// Tells if `byte` may start an HTTP request, methods being uppercase
+pub(crate) fn sniff(byte: u8) -> bool {
+    byte.is_ascii_uppercase()
+}
+
+/// The request line and headers of an HTTP request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// Parses a request head, the empty line ending it included or not
+    pub fn parse(head: &[u8]) -> io::Result<Self> {
+        let head = std::str::from_utf8(head).map_err(|err| invalid(err.to_string()))?;
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let (Some(method), Some(target), Some(version), None) = (
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+        ) else {
+            return Err(invalid(format!("Invalid request line in {head:?}")));
+        };
+
+        let headers = lines
+            .take_while(|line| !line.is_empty())
+            .map(|line| {
+                let (name, value) = line
+                    .split_once(':')
+                    .ok_or_else(|| invalid(format!("Invalid header {line:?}")))?;
+                Ok((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers,
+        })
+    }
+
+    /// Returns the value of the first header called `name`
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Decodes the username and password of `Proxy-Authorization: Basic`
+    pub fn basic_credentials(&self) -> Option<(String, String)> {
+        let (scheme, credentials) = self.header("proxy-authorization")?.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+
+        let credentials = String::from_utf8(BASE64.decode(credentials.trim()).ok()?).ok()?;
+        let (username, password) = credentials.split_once(':')?;
+        Some((username.to_string(), password.to_string()))
+    }
+
+    /// Tells if this is a `CONNECT` request
+    pub fn is_connect(&self) -> bool {
+        self.method.eq_ignore_ascii_case("CONNECT")
+    }
+
+    /// Reads where the request goes: the authority of a `CONNECT`, or the
+    /// host of an `http://` absolute URI along with its path
+    pub fn destination(&self) -> io::Result<(Destination, &str)> {
+        if self.is_connect() {
+            return Ok((self.target.parse()?, ""));
+        }
+
+        let unsupported = || {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Only http:// URIs are forwarded ({})", self.target),
+            )
+        };
+        let rest = self
+            .target
+            .get(..7)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+            .map(|_| &self.target[7..])
+            .ok_or_else(unsupported)?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let authority = authority
+            .rsplit_once('@')
+            .map_or(authority, |(_, host)| host);
+
+        // IPv6 literals keep their colons between brackets
+        let has_port = authority
+            .rsplit_once(':')
+            .is_some_and(|(host, _)| !host.starts_with('[') || host.ends_with(']'));
+        let dst = if has_port {
+            authority.parse()?
+        } else {
+            format!("{authority}:80").parse()?
+        };
+
+        Ok((dst, path))
+    }
+
+    /// Writes the request for the origin server: the path alone as target,
+    /// no proxy headers and the connection closing after the response
+    pub fn origin_form(&self, path: &str) -> Vec<u8> {
+        let mut head = format!("{} {path} {}\r\n", self.method, self.version);
+        for (name, value) in &self.headers {
+            if !HOP_BY_HOP.contains(&name.to_ascii_lowercase().as_str()) {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        head.into_bytes()
+    }
+}
+
+/// Returns the length of the request head at the start of `buf`, if whole
+pub(crate) fn head_len(buf: &[u8]) -> Option<usize> {
+    let end = buf.windows(4).position(|window| window == b"\r\n\r\n")?;
+    Some(end + 4)
+}
+
+/// Reads on until `buf` holds a whole request head, and returns its length
+pub(crate) async fn read_head<S: Stream>(stream: &mut S, buf: &mut Vec<u8>) -> io::Result<usize> {
+    loop {
+        if let Some(len) = head_len(buf) {
+            return Ok(len);
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(invalid(format!("Request head over {MAX_HEAD} bytes")));
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Writes a response without a body, `status` being anything the proxy
+/// itself answers with
+pub(crate) fn response(status: u16) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {status} {}\r\n", reason(status));
+    if status == 407 {
+        response.push_str("Proxy-Authenticate: Basic realm=\"proksi\"\r\n");
+    }
+    if status != 200 {
+        response.push_str("Content-Length: 0\r\nConnection: close\r\n");
+    }
+    response.push_str("\r\n");
+    response.into_bytes()
+}
+
+/// Writes a response carrying `body`, the connection closing after it
+pub(crate) fn response_with_body(status: u16, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let reason = match status {
+        200 => "OK",
+        _ => reason(status),
+    };
+    let mut response = format!("HTTP/1.1 {status} {reason}\r\n");
+    if status == 401 {
+        response.push_str("WWW-Authenticate: Bearer realm=\"proksi\"\r\n");
+    }
+    let mut response = format!(
+        "{response}Content-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "Connection established",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        407 => "Proxy Authentication Required",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    }
+}
+
+/// Maps a SOCKS5 reply to the status of the matching HTTP response
+pub(crate) fn status(rep: u8) -> u16 {
+    match rep {
+        reply_opt::SUCCEEDED => 200,
+        reply_opt::CONNECTION_NOT_ALLOWED => 403,
+        reply_opt::TTL_EXPIRED => 504,
+        reply_opt::COMMAND_NOT_SUPPORTED | reply_opt::ADDRESS_TYPE_NOT_SUPPORTED => 501,
+        _ => 502,
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
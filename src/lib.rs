@@ -1,20 +1,69 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use socks_rs::{
     auth::{AuthRequest, AuthResponse},
     establish::{method, EstablishRequest, EstablishResponse},
     reply::{reply_opt, Reply},
     request::{addr_type, command, Request},
-    Sendible, SOCKS_VERSION,
+    socks4, Sendible, SOCKS_VERSION,
 };
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::signal;
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    time,
 };
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
 
+pub mod access_log;
+pub mod admin;
+pub mod cidr;
+pub mod connection;
+pub mod destination;
+pub mod egress;
+pub mod handle;
+pub mod hosts;
+mod http;
+pub mod listener;
+pub mod metrics;
+pub mod proxy_protocol;
+mod relay;
+pub mod resolve;
+pub mod route;
+pub mod session;
+pub mod timeout;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(unix)]
+pub mod unix;
+pub mod upstream;
 pub mod user;
-use user::User;
+use access_log::{AccessLog, AccessLogger};
+use admin::Admin;
+use connection::{ConnectionInfo, Stream};
+use destination::Destination;
+use egress::{Affinity, Egress};
+use handle::ServerHandle;
+use hosts::{Hosts, Target};
+use listener::Listener;
+use metrics::{Active, Metrics, Prometheus};
+use resolve::DnsPolicy;
+use route::{Action, RouteQuery, Routes, Rule};
+use session::{Phase, Registry, Session};
+use timeout::Timeouts;
+#[cfg(feature = "tls")]
+use tls::Tls;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+#[cfg(unix)]
+use unix::UnixListen;
+use upstream::{Upstream, UpstreamError};
+use user::{User, Users};
 
 #[macro_use]
 mod macros {
@@ -38,15 +87,128 @@ mod macros {
     }
 }
 
+/// Protocol a client speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Socks4,
+    Socks5,
+    HttpConnect,
+    HttpForward,
+}
+
+/// What a client asks for, whichever the protocol
+struct ClientRequest {
+    protocol: Protocol,
+    cmd: u8,
+    dst: io::Result<Destination>,
+
+    /// data for the destination, sent ahead of the relay
+    early: Vec<u8>,
+}
+
+/// Ends a session once dropped, however its task ends, even when dropped on
+/// shutdown: unregisters it, counts it and writes it to the access log
+struct Ending {
+    session: Arc<Session>,
+    sessions: Registry,
+    metrics: Arc<Metrics>,
+    access_logger: Option<Arc<AccessLogger>>,
+
+    /// why the session ended, unset until it ran to the end
+    close: Option<String>,
+    _active: Active,
+}
+
+impl Ending {
+    /// Records how the session ended, the reply to a failed request
+    /// included
+    fn finish(&mut self, result: &io::Result<()>) {
+        if let Err(err) = result {
+            self.session.update(|state| {
+                if state.command.is_some() && state.reply.is_none() {
+                    state.reply = Some(reply_code(err));
+                }
+            });
+        }
+
+        self.close = Some(match result {
+            Ok(()) => "closed".to_string(),
+            Err(err) => err.to_string(),
+        });
+    }
+}
+
+impl Drop for Ending {
+    fn drop(&mut self) {
+        let session = &self.session;
+        self.sessions.remove(session.id);
+
+        let state = session.state();
+        self.metrics.session_ended(
+            state.user.as_deref(),
+            state.command.zip(state.reply),
+            session.traffic.up(),
+            session.traffic.down(),
+        );
+
+        let Some(ref logger) = self.access_logger else {
+            return;
+        };
+        let close = self.close.as_deref().unwrap_or("Server shut down");
+        if let Err(err) = logger.log(session, close) {
+            warn!(error = %err, "Failed to write the access log");
+        }
+    }
+}
+
+/// Stands in for the acceptor of TLS listeners without the `tls` feature
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+enum TlsAcceptor {}
+
 #[allow(dead_code)]
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Server {
     #[serde(skip)]
     version: u8,
-    pub addr: SocketAddr,
+    #[serde(deserialize_with = "listener::one_or_many")]
+    pub addr: Vec<Listener>,
     auth: Vec<u8>,
     #[serde(default)]
-    allowed_users: Vec<User>,
+    allowed_users: Users,
+    #[serde(default)]
+    hosts: Hosts,
+    #[serde(default)]
+    dns_policy: DnsPolicy,
+    #[serde(default)]
+    upstream: Vec<Upstream>,
+    #[serde(default)]
+    upstreams: HashMap<String, Vec<Upstream>>,
+    #[serde(default)]
+    routes: Routes,
+    #[serde(default)]
+    egress: Egress,
+    #[serde(default)]
+    timeouts: Timeouts,
+    #[serde(default)]
+    forward_http: bool,
+    #[serde(default)]
+    tor_resolve: bool,
+    #[serde(default)]
+    access_log: Option<AccessLog>,
+    #[serde(default)]
+    prometheus: Option<Prometheus>,
+    #[serde(default)]
+    admin: Option<Admin>,
+    #[cfg(unix)]
+    #[serde(default)]
+    unix: Option<UnixListen>,
+    #[serde(skip)]
+    metrics: Arc<Metrics>,
+    #[serde(skip)]
+    access_logger: Option<Arc<AccessLogger>>,
+    #[serde(skip)]
+    sessions: Registry,
 }
 
 impl Server {
@@ -59,183 +221,1144 @@ impl Server {
         Ok(Self {
             version: SOCKS_VERSION,
             auth,
-            addr,
-            allowed_users,
+            addr: vec![Listener::new(addr)],
+            allowed_users: Users::from(allowed_users),
+            hosts: Hosts::new(),
+            dns_policy: DnsPolicy::default(),
+            upstream: vec![],
+            upstreams: HashMap::new(),
+            routes: Routes::new(),
+            egress: Egress::default(),
+            timeouts: Timeouts::default(),
+            forward_http: false,
+            tor_resolve: false,
+            access_log: None,
+            prometheus: None,
+            admin: None,
+            #[cfg(unix)]
+            unix: None,
+            metrics: Arc::default(),
+            access_logger: None,
+            sessions: Registry::default(),
         })
     }
 
-    /// Start the server and listen for new connections
+    /// Adds another listener
+    pub fn with_listener(mut self, listener: Listener) -> Self {
+        self.addr.push(listener);
+        self
+    }
+
+    /// Sets the chain of parent proxies CONNECT requests go through,
+    /// an empty chain meaning direct connections
+    pub fn with_upstream(mut self, upstream: Vec<Upstream>) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    /// Sets how domain names are resolved, unless overridden per user
+    pub fn with_dns_policy(mut self, dns_policy: DnsPolicy) -> Self {
+        self.dns_policy = dns_policy;
+        self
+    }
+
+    /// Sets the static host overrides
+    pub fn with_hosts(mut self, hosts: Hosts) -> Self {
+        self.hosts = hosts;
+        self
+    }
+
+    /// Returns a handle to the host overrides, which can be used
+    /// to update them while the server is running
+    pub fn hosts(&self) -> Hosts {
+        self.hosts.clone()
+    }
+
+    /// Adds a named upstream chain routing rules can refer to
+    pub fn with_named_upstream(mut self, name: &str, upstream: Vec<Upstream>) -> Self {
+        self.upstreams.insert(name.to_string(), upstream);
+        self
+    }
+
+    /// Sets the routing rules
+    pub fn with_routes(self, rules: Vec<Rule>) -> Self {
+        self.routes.replace(rules);
+        self
+    }
+
+    /// Returns a handle to the routing table, which can be used to update
+    /// the rules while the server is running or to dry-run requests
+    pub fn routes(&self) -> Routes {
+        self.routes.clone()
+    }
+
+    /// Returns a handle to the allowed users, which can be used to add,
+    /// remove or disable users while the server is running
+    pub fn users(&self) -> Users {
+        self.allowed_users.clone()
+    }
+
+    /// Sets the source address and interface of outbound connections,
+    /// unless overridden per user
+    pub fn with_egress(mut self, egress: Egress) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    /// Sets the session timeouts
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Forwards plain HTTP requests to absolute URIs, on top of serving
+    /// `CONNECT` to HTTP clients
+    pub fn with_forward_http(mut self, forward_http: bool) -> Self {
+        self.forward_http = forward_http;
+        self
+    }
+
+    /// Answers Tor's RESOLVE and RESOLVE_PTR commands, letting SOCKS5
+    /// clients look names and addresses up through the proxy
+    pub fn with_tor_resolve(mut self, tor_resolve: bool) -> Self {
+        self.tor_resolve = tor_resolve;
+        self
+    }
+
+    /// Logs every finished session, once the server runs
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Serves the metrics over HTTP, for Prometheus to scrape
+    pub fn with_prometheus(mut self, prometheus: Prometheus) -> Self {
+        self.prometheus = Some(prometheus);
+        self
+    }
+
+    /// Serves the admin API, for a control plane to drive the server
+    pub fn with_admin(mut self, admin: Admin) -> Self {
+        self.admin = Some(admin);
+        self
+    }
+
+    /// Also listens on a Unix domain socket
+    #[cfg(unix)]
+    pub fn with_unix(mut self, unix: UnixListen) -> Self {
+        self.unix = Some(unix);
+        self
+    }
+
+    /// Returns the server counters
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Start the server and listen for new connections, until it is shut down
     pub async fn start(self) -> io::Result<()> {
-        let listener = TcpListener::bind(&self.addr).await?;
+        self.spawn().await?.closed().await;
+        Ok(())
+    }
+
+    /// Start the server in the background, returning a handle to control it.
+    ///
+    /// The listeners are bound before returning, so with port 0 the handle
+    /// tells the ports actually picked.
+    pub async fn spawn(self) -> io::Result<ServerHandle> {
+        let mut listeners = Vec::with_capacity(self.addr.len());
+        for listener in &self.addr {
+            listeners.push(TcpListener::bind(listener.addr).await?);
+        }
+
+        self.run(listeners)
+    }
+
+    /// Serve the connections of an already bound listener in the background,
+    /// along with the Unix socket if there is one, returning a handle to
+    /// control the server.
+    ///
+    /// The listener gets the settings of the first listener definition,
+    /// the other ones are ignored.
+    pub fn serve(mut self, listener: TcpListener) -> io::Result<ServerHandle> {
+        self.addr.truncate(1);
+        if self.addr.is_empty() {
+            self.addr.push(Listener::new(listener.local_addr()?));
+        }
+
+        self.run(vec![listener])
+    }
+
+    /// Serves `listeners`, one for each listener definition
+    fn run(mut self, listeners: Vec<TcpListener>) -> io::Result<ServerHandle> {
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No listener to serve",
+            ));
+        }
+
+        for (definition, listener) in self.addr.iter_mut().zip(&listeners) {
+            definition.addr = listener.local_addr()?;
+        }
+        let metrics_listener = match self.prometheus {
+            Some(ref mut prometheus) => {
+                let listener = std::net::TcpListener::bind(prometheus.addr)?;
+                listener.set_nonblocking(true)?;
+                prometheus.addr = listener.local_addr()?;
+                Some(TcpListener::from_std(listener)?)
+            }
+            None => None,
+        };
+        let admin_listener = self.admin.as_mut().map(Admin::bind).transpose()?;
+
+        let handle = ServerHandle::new(self.addr.iter().map(|listener| listener.addr).collect())
+            .with_metrics_addr(self.prometheus.as_ref().map(|prometheus| prometheus.addr))
+            .with_admin_addr(self.admin.as_ref().and_then(Admin::addr))
+            .with_sessions(self.sessions.clone());
+        if let Some(listener) = metrics_listener {
+            let per_user = self
+                .prometheus
+                .as_ref()
+                .is_some_and(|prometheus| prometheus.per_user);
+            let metrics = Arc::clone(&self.metrics);
+            handle.spawn(metrics::serve(listener, metrics, per_user, handle.clone()));
+        }
+
+        let mut acceptors = Vec::with_capacity(self.addr.len());
+        for _listener in &self.addr {
+            #[cfg(feature = "tls")]
+            acceptors.push(_listener.tls.as_ref().map(Tls::acceptor).transpose()?);
+            #[cfg(not(feature = "tls"))]
+            acceptors.push(None);
+        }
+
+        #[cfg(unix)]
+        let unix = self.unix.as_ref().map(UnixListen::bind).transpose()?;
+
+        self.access_logger = self
+            .access_log
+            .as_ref()
+            .map(AccessLog::open)
+            .transpose()?
+            .map(Arc::new);
+        #[cfg(unix)]
+        if let Some(logger) = self
+            .access_logger
+            .as_ref()
+            .filter(|logger| logger.is_file())
+        {
+            let hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+            handle.spawn(reopen_on_hangup(Arc::clone(logger), hangup, handle.clone()));
+        }
+
         let server = Arc::new(self);
+        if let Some(listener) = admin_listener {
+            handle.spawn(admin::serve(listener, Arc::clone(&server), handle.clone()));
+        }
 
+        for (index, (listener, tls)) in listeners.into_iter().zip(acceptors).enumerate() {
+            handle.spawn(Arc::clone(&server).accept(listener, index, tls, handle.clone()));
+        }
+
+        #[cfg(unix)]
+        if let Some(unix) = unix {
+            handle.spawn(server.accept_unix(unix, handle.clone()));
+        }
+
+        Ok(handle)
+    }
+
+    async fn accept(
+        self: Arc<Self>,
+        listener: TcpListener,
+        index: usize,
+        #[cfg_attr(not(feature = "tls"), allow(unused_variables))] tls: Option<TlsAcceptor>,
+        handle: ServerHandle,
+    ) {
         loop {
-            let (mut stream, addr) = listener.accept().await?;
-
-            println!("Connection from {addr:?}");
-
-            let server = Arc::clone(&server);
-            tokio::spawn(async move {
-                server
-                    .establish_connection_handler(&mut stream)
-                    .await
-                    .unwrap_or_else(|err| {
-                        eprintln!("Error: {err}");
-                    })
-            });
+            let (stream, addr) = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        warn!(error = %err, "Failed to accept a connection");
+                        // e.g. out of file descriptors, give sessions a chance to end
+                        time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+                _ = handle.stopped() => return,
+            };
+
+            if handle.is_draining() {
+                debug!(%addr, "Draining, dropped a connection");
+                continue;
+            }
+
+            let info = match ConnectionInfo::tcp(&stream) {
+                Ok(info) => info,
+                Err(err) => {
+                    warn!(error = %err, %addr, "Dropped a connection");
+                    continue;
+                }
+            };
+
+            #[cfg(feature = "tls")]
+            if let Some(ref acceptor) = tls {
+                self.tls_session(&handle, acceptor.clone(), stream, info, index);
+                continue;
+            }
+
+            self.session(&handle, stream, info, Some(index));
         }
     }
 
-    async fn establish_connection_handler(
+    #[cfg(unix)]
+    async fn accept_unix(
         self: Arc<Self>,
-        stream: &mut TcpStream,
+        listener: tokio::net::UnixListener,
+        handle: ServerHandle,
+    ) {
+        loop {
+            let stream = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        warn!(error = %err, "Failed to accept a connection");
+                        time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+                _ = handle.stopped() => return,
+            };
+
+            if handle.is_draining() {
+                debug!("Draining, dropped a connection");
+                continue;
+            }
+
+            let info = match ConnectionInfo::unix(&stream) {
+                Ok(info) => info,
+                Err(err) => {
+                    warn!(error = %err, "Dropped a connection");
+                    continue;
+                }
+            };
+
+            self.session(&handle, stream, info, None);
+        }
+    }
+
+    fn session<S: Stream + 'static>(
+        self: &Arc<Self>,
+        handle: &ServerHandle,
+        mut stream: S,
+        info: ConnectionInfo,
+        listener: Option<usize>,
+    ) {
+        let server = Arc::clone(self);
+        let (session, span, mut ending) = self.new_session(&info);
+        handle.spawn(
+            async move {
+                let listener = listener.and_then(|index| server.addr.get(index));
+                let result = async {
+                    let info = server.proxy_header(&mut stream, info, listener).await?;
+                    server
+                        .serve_session(&mut stream, info, listener, &session)
+                        .await
+                };
+
+                let result = session.run(result).await;
+                ending.finish(&result);
+                session_ended(result)
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Runs a session inside TLS, the TLS handshake being subject to the
+    /// handshake timeout as well
+    #[cfg(feature = "tls")]
+    fn tls_session(
+        self: &Arc<Self>,
+        handle: &ServerHandle,
+        acceptor: TlsAcceptor,
+        mut stream: TcpStream,
+        info: ConnectionInfo,
+        index: usize,
+    ) {
+        let server = Arc::clone(self);
+        let (session, span, mut ending) = self.new_session(&info);
+        handle.spawn(
+            async move {
+                let listener = server.addr.get(index);
+                let result = async {
+                    // balancers put their header ahead of the TLS handshake
+                    let info = server.proxy_header(&mut stream, info, listener).await?;
+
+                    let accept = acceptor.accept(stream);
+                    let mut stream = server
+                        .within_handshake("TLS handshake", "tls", accept)
+                        .await?;
+
+                    let info = match tls::peer_cert(stream.get_ref().1) {
+                        Some(cert) => info.with_cert(cert),
+                        None => info,
+                    };
+                    debug!(subject = ?info.cert.as_ref().map(|cert| &cert.subject), "TLS established");
+
+                    server
+                        .serve_session(&mut stream, info, listener, &session)
+                        .await
+                };
+
+                let result = session.run(result).await;
+                ending.finish(&result);
+                session_ended(result)
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Runs a whole SOCKS session over `stream`, for embedding the server
+    /// behind another accept loop or transport
+    pub async fn serve_connection<S: Stream>(
+        &self,
+        stream: &mut S,
+        info: ConnectionInfo,
     ) -> io::Result<()> {
+        let (session, span, mut ending) = self.new_session(&info);
+        let result = session
+            .run(self.serve_session(stream, info, None, &session))
+            .instrument(span)
+            .await;
+
+        ending.finish(&result);
+        result
+    }
+
+    /// Starts and registers a new session, along with its span and what
+    /// ends it once dropped. The user, command and destination are recorded
+    /// in both as the session learns them.
+    fn new_session(&self, info: &ConnectionInfo) -> (Arc<Session>, Span, Ending) {
+        let (id, active) = self.metrics.session_started();
+        let session = Arc::new(Session::new(id, info.peer));
+        self.sessions.insert(Arc::clone(&session));
+        let span = info_span!(
+            "session",
+            id = session.id,
+            peer = field::Empty,
+            user = field::Empty,
+            command = field::Empty,
+            destination = field::Empty,
+        );
+        if let Some(peer) = info.peer {
+            span.record("peer", field::display(peer));
+        }
+        debug!(parent: &span, cred = ?info.cred, "Session started");
+        let ending = Ending {
+            session: Arc::clone(&session),
+            sessions: self.sessions.clone(),
+            metrics: Arc::clone(&self.metrics),
+            access_logger: self.access_logger.clone(),
+            close: None,
+            _active: active,
+        };
+        (session, span, ending)
+    }
+
+    async fn serve_session<S: Stream>(
+        &self,
+        stream: &mut S,
+        info: ConnectionInfo,
+        listener: Option<&Listener>,
+        session: &Session,
+    ) -> io::Result<()> {
+        session.update(|state| state.peer = info.peer);
+        let run = self.establish_connection_handler(stream, &info, listener, session);
+
+        match self.timeouts.lifetime {
+            Some(lifetime) => time::timeout(lifetime, run)
+                .await
+                .unwrap_or_else(|_| error!("Session lifetime of {lifetime:?} exceeded")),
+            None => run.await,
+        }
+    }
+
+    async fn establish_connection_handler<S: Stream>(
+        &self,
+        stream: &mut S,
+        info: &ConnectionInfo,
+        listener: Option<&Listener>,
+        session: &Session,
+    ) -> io::Result<()> {
+        let handshake = self.handshake(stream, info, listener);
+        let (user, buf) = self
+            .within_handshake("Handshake", "protocol", handshake)
+            .await?;
+        if let Some(ref user) = user {
+            self.metrics.authenticated(&user.username);
+            Span::current().record("user", user.username.as_str());
+            session.update(|state| state.user = Some(user.username.clone()));
+        }
+        session.update(|state| state.phase = Phase::Request);
+        debug!("Handshake done");
+
+        let request = self.read_request(&buf)?;
+        self.request_handler(stream, info, listener, user.as_ref(), request, session)
+            .await
+    }
+
+    /// Parses the request a handshake ended with, telling the protocol by
+    /// its first byte
+    fn read_request(&self, buf: &[u8]) -> io::Result<ClientRequest> {
+        let request = match buf.first() {
+            Some(&socks4::SOCKS4_VERSION) => {
+                let request = socks4::Request::deserialize(buf)?;
+                ClientRequest {
+                    protocol: Protocol::Socks4,
+                    cmd: request.cmd,
+                    dst: Destination::from_socks4(&request),
+                    early: vec![],
+                }
+            }
+            Some(&byte) if http::sniff(byte) => {
+                let len = http::head_len(buf).unwrap_or(buf.len());
+                let head = http::RequestHead::parse(&buf[..len])?;
+                let rest = &buf[len..];
+
+                let (protocol, dst, early) = match head.destination() {
+                    Ok((dst, _)) if head.is_connect() => {
+                        (Protocol::HttpConnect, Ok(dst), rest.to_vec())
+                    }
+                    Ok(_) if !self.forward_http => {
+                        let err = io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            format!("No forwarding of plain HTTP ({})", head.target),
+                        );
+                        (Protocol::HttpForward, Err(err), vec![])
+                    }
+                    Ok((dst, path)) => {
+                        let early = [head.origin_form(path).as_slice(), rest].concat();
+                        (Protocol::HttpForward, Ok(dst), early)
+                    }
+                    Err(err) if head.is_connect() => (Protocol::HttpConnect, Err(err), vec![]),
+                    Err(err) => (Protocol::HttpForward, Err(err), vec![]),
+                };
+
+                ClientRequest {
+                    protocol,
+                    cmd: command::CONNECT,
+                    dst,
+                    early,
+                }
+            }
+            _ => {
+                whole(buf, request_len(buf), "SOCKS5 request")?;
+                let request = Request::deserialize(buf)?;
+                ClientRequest {
+                    protocol: Protocol::Socks5,
+                    cmd: request.cmd,
+                    dst: Destination::from_request(&request),
+                    early: vec![],
+                }
+            }
+        };
+
+        Ok(request)
+    }
+
+    /// Runs `step` of the handshake, failing once the handshake timeout is up.
+    /// Failures other than rejections are counted as `kind`.
+    async fn within_handshake<T>(
+        &self,
+        step: &str,
+        kind: &'static str,
+        future: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        let result = match self.timeouts.handshake {
+            Some(limit) => match time::timeout(limit, future).await {
+                Ok(result) => result,
+                Err(_) => {
+                    self.metrics.handshake_failed("timeout");
+                    error!("{step} timed out after {limit:?}")
+                }
+            },
+            None => future.await,
+        };
+
+        if result
+            .as_ref()
+            .is_err_and(|err| err.kind() != io::ErrorKind::PermissionDenied)
+        {
+            self.metrics.handshake_failed(kind);
+        }
+        result
+    }
+
+    /// Counts a client rejected for `reason`, returning the error ending
+    /// its session
+    fn reject(&self, reason: &'static str, msg: String) -> io::Error {
+        self.metrics.rejected_for(reason);
+        io::Error::new(io::ErrorKind::PermissionDenied, msg)
+    }
+
+    /// Reads the PROXY protocol header of connections from the load
+    /// balancers the listener trusts, the client it tells of becoming the
+    /// peer of the session
+    async fn proxy_header<S: Stream>(
+        &self,
+        stream: &mut S,
+        mut info: ConnectionInfo,
+        listener: Option<&Listener>,
+    ) -> io::Result<ConnectionInfo> {
+        let trusted = listener.zip(info.peer).is_some_and(|(listener, peer)| {
+            listener
+                .proxy_protocol
+                .iter()
+                .any(|cidr| cidr.contains(peer.ip()))
+        });
+        if !trusted {
+            return Ok(info);
+        }
+
+        let header = proxy_protocol::read_header(stream);
+        if let Some(addrs) = self
+            .within_handshake("PROXY protocol header", "proxy_protocol", header)
+            .await?
+        {
+            debug!(balancer = ?info.peer, "Client behind a load balancer");
+            Span::current().record("peer", field::display(addrs.source));
+            info.peer = Some(addrs.source);
+        }
+
+        Ok(info)
+    }
+
+    /// Greets the client, authenticates it and reads its request.
+    ///
+    /// SOCKS4 clients send their request right away and cannot authenticate,
+    /// so they are only served where no authentication is required, or with
+    /// a client certificate standing for a user. HTTP clients send theirs
+    /// right away too, along with their credentials.
+    async fn handshake<S: Stream>(
+        &self,
+        stream: &mut S,
+        info: &ConnectionInfo,
+        listener: Option<&Listener>,
+    ) -> io::Result<(Option<User>, Vec<u8>)> {
         let mut buf = Vec::with_capacity(50);
         stream.read_buf(&mut buf).await?;
-        let establish_request = EstablishRequest::deserialize(&buf).unwrap();
 
-        let establish_method = self
-            .auth
-            .iter()
-            .max_by_key(|&k| establish_request.methods.contains(k))
-            .copied()
-            .unwrap_or(method::NO_ACCEPTABLE_METHODS);
+        let auth = listener
+            .and_then(|listener| listener.auth.as_ref())
+            .unwrap_or(&self.auth);
+
+        if buf.first() == Some(&socks4::SOCKS4_VERSION) {
+            if !auth.contains(&method::NO_AUTHENTICATION_REQUIRED) && self.cert_user(info).is_none()
+            {
+                let reply = socks4::Reply::new(socks4::reply_code::REJECTED, [0; 4], 0);
+                stream.write_all(&reply.serialize()?).await?;
+                let msg = "SOCKS4 needs NO AUTHENTICATION REQUIRED".to_string();
+                return Err(self.reject("method", msg));
+            }
+            return Ok((self.peer_user(info), buf));
+        }
+
+        if buf.first().is_some_and(|&byte| http::sniff(byte)) {
+            return self.http_handshake(stream, info, auth, buf).await;
+        }
+
+        whole(&buf, greeting_len(&buf), "SOCKS5 greeting")?;
+        let establish_request = EstablishRequest::deserialize(&buf)?;
+        // a certificate standing for a user replaces the password
+        let no_auth = establish_request
+            .methods
+            .contains(&method::NO_AUTHENTICATION_REQUIRED);
+        let establish_method = if no_auth && self.cert_user(info).is_some() {
+            method::NO_AUTHENTICATION_REQUIRED
+        } else {
+            auth.iter()
+                .max_by_key(|&k| establish_request.methods.contains(k))
+                .copied()
+                .unwrap_or(method::NO_ACCEPTABLE_METHODS)
+        };
 
         stream
             .write_all(&EstablishResponse::new(establish_method).serialize()?)
-            .await
-            .unwrap();
+            .await?;
 
-        match establish_method {
-            method::USERNAME_PASSWORD => self.auth_request(stream).await?,
+        let user = match establish_method {
+            method::USERNAME_PASSWORD => Some(self.auth_request(stream).await?),
             method::GSSAPI => panic!("No support for GSSAPI yet"),
-            method::NO_ACCEPTABLE_METHODS => error!("NO ACCEPTABLE METHODS"),
-            _ => (),
+            method::NO_ACCEPTABLE_METHODS => {
+                return Err(self.reject("method", "NO ACCEPTABLE METHODS".to_string()))
+            }
+            _ => self.peer_user(info),
+        };
+
+        let mut buf = Vec::with_capacity(50);
+        stream.read_buf(&mut buf).await?;
+
+        Ok((user, buf))
+    }
+
+    /// Reads the head of an HTTP request and authenticates the client by its
+    /// `Proxy-Authorization`, which may only be left out where no
+    /// authentication is required
+    async fn http_handshake<S: Stream>(
+        &self,
+        stream: &mut S,
+        info: &ConnectionInfo,
+        auth: &[u8],
+        mut buf: Vec<u8>,
+    ) -> io::Result<(Option<User>, Vec<u8>)> {
+        let head = match http::read_head(stream, &mut buf).await {
+            Ok(len) => http::RequestHead::parse(&buf[..len]),
+            Err(err) => Err(err),
+        };
+        let head = match head {
+            Ok(head) => head,
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                stream.write_all(&http::response(400)).await?;
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        };
+
+        let credentials = head.basic_credentials();
+        let user = match &credentials {
+            Some((username, password)) => self.find_user(username, password),
+            None if auth.contains(&method::NO_AUTHENTICATION_REQUIRED)
+                || self.cert_user(info).is_some() =>
+            {
+                return Ok((self.peer_user(info), buf))
+            }
+            None => None,
         };
 
-        self.request_handler(stream).await
+        match user {
+            Some(user) => Ok((Some(user), buf)),
+            None => {
+                stream.write_all(&http::response(407)).await?;
+                let username = credentials.as_ref().map(|(username, _)| username.as_str());
+                self.metrics.auth_failed(username.unwrap_or_default());
+                let msg = format!("Proxy authentication failed for {}", head.target);
+                Err(self.reject("auth", msg))
+            }
+        }
+    }
+
+    /// Finds the allowed user clients skipping authentication stand for,
+    /// by their Unix socket credentials or their TLS certificate
+    fn peer_user(&self, info: &ConnectionInfo) -> Option<User> {
+        self.allowed_users
+            .find(|user| info.cred.is_some_and(|cred| user.uid == Some(cred.uid)))
+            .or_else(|| self.cert_user(info))
     }
 
-    async fn auth_request(&self, stream: &mut TcpStream) -> io::Result<()> {
+    /// Finds the allowed user a verified client certificate stands for, by
+    /// its subject, or by its names for users without a `cert_subject`
+    fn cert_user(&self, info: &ConnectionInfo) -> Option<User> {
+        let cert = info.cert.as_ref()?;
+        self.allowed_users.find(|user| match user.cert_subject {
+            Some(ref subject) => cert.has_subject(subject),
+            None => cert.names.contains(&user.username),
+        })
+    }
+
+    /// Finds the allowed user with these credentials
+    fn find_user(&self, username: &str, password: &str) -> Option<User> {
+        self.allowed_users
+            .find(|allowed| allowed.username == username && allowed.password == password)
+    }
+
+    async fn auth_request<S: Stream>(&self, stream: &mut S) -> io::Result<User> {
         use std::str;
 
         let mut buf = Vec::with_capacity(100);
         stream.read_buf(&mut buf).await?;
 
+        whole(&buf, auth_len(&buf), "SOCKS5 authentication")?;
         let auth_request = AuthRequest::deserialize(&buf)?;
 
-        let user = User::new(
-            str::from_utf8(auth_request.uname).unwrap(),
-            str::from_utf8(auth_request.passwd).unwrap(),
-        );
+        let utf8 = |field: &[u8]| {
+            str::from_utf8(field)
+                .map(str::to_string)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        };
+        let user = User::new(&utf8(auth_request.uname)?, &utf8(auth_request.passwd)?);
 
-        let response = AuthResponse::new(!self.allowed_users.contains(&user) as u8);
+        let allowed = self.find_user(&user.username, &user.password);
+
+        let response = AuthResponse::new(allowed.is_none() as u8);
 
         stream.write_all(&response.serialize()?).await?;
 
-        if response.status != 0 {
-            error!(
-                "({username}:{password}) WRONG user/password",
-                username = user.username,
-                password = user.password
-            )
+        match allowed {
+            Some(allowed) => Ok(allowed),
+            // the attempted password stays out of logs
+            None => {
+                self.metrics.auth_failed(&user.username);
+                let msg = format!("({}) WRONG user/password", user.username);
+                Err(self.reject("auth", msg))
+            }
         }
-
-        Ok(())
     }
 
-    async fn request_handler(self: Arc<Self>, stream: &mut TcpStream) -> io::Result<()> {
-        let mut buf = Vec::with_capacity(50);
-        stream.read_buf(&mut buf).await?;
-        let request = Request::deserialize(&buf)?;
+    async fn request_handler<S: Stream>(
+        &self,
+        stream: &mut S,
+        info: &ConnectionInfo,
+        listener: Option<&Listener>,
+        user: Option<&User>,
+        request: ClientRequest,
+        session: &Session,
+    ) -> io::Result<()> {
+        let ClientRequest {
+            protocol,
+            cmd,
+            dst: requested,
+            early,
+        } = request;
 
-        match request.cmd {
-            command::CONNECT => self.connect_request(stream, request).await?,
-            #[cfg(feature = "bind")]
-            command::BIND => self.bind_request(stream).await?,
-            #[cfg(not(feature = "bind"))]
-            command::BIND => panic!("No BIND command!"),
-            command::UDP_ASSOCIATE => panic!("No UDP command!"),
-            cmd => error!("Command {cmd} not available!"),
-        };
+        let span = Span::current();
+        span.record("command", command_name(cmd));
+        session.update(|state| state.command = Some(command_name(cmd)));
 
-        Ok(())
-    }
+        let requested = match requested {
+            Ok(requested) => requested,
+            Err(err) => return reply_error(stream, protocol, err).await,
+        };
+        span.record("destination", field::display(&requested));
+        session.update(|state| state.destination = Some(requested.to_string()));
+        info!(?protocol, "Request received");
 
-    async fn connect_request(
-        &self,
-        stream: &mut TcpStream,
-        request: Request<'_>,
-    ) -> io::Result<()> {
-        let socket_addr = stream.local_addr()?;
-        let (atyp, ip) = ip_octs!(socket_addr);
-        let port = socket_addr.port();
+        let query = RouteQuery {
+            dst: &requested,
+            user: user.map(|user| user.username.as_str()),
+            client: info.peer.map(|addr| addr.ip()),
+            uid: info.cred.map(|cred| cred.uid),
+        };
+        let rule = listener
+            .and_then(|listener| listener.rules.evaluate(&query))
+            .or_else(|| self.routes.evaluate(&query))
+            .map(|route| route.rule);
 
-        let (dst_ip, dst_port) = (request.dst_addr, request.dst_port);
+        if rule.as_ref().map(|rule| &rule.action) == Some(&Action::Reject) {
+            let err = self.reject("rule", format!("Request to {requested} rejected"));
+            return reply_error(stream, protocol, err).await;
+        }
 
-        let mut reply = Reply::new(reply_opt::SUCCEEDED, atyp, &ip, port);
+        let dns_policy = self.dns_policy(listener, user);
+        let dst = match self.destination(requested, dns_policy) {
+            Ok(dst) => dst,
+            Err(err) => return reply_error(stream, protocol, err).await,
+        };
 
-        let dst_socket = match request.atyp {
-            addr_type::IP_V4 => {
-                SocketAddr::from((TryInto::<[u8; 4]>::try_into(dst_ip).unwrap(), dst_port))
+        match cmd {
+            command::CONNECT => {
+                let dst_stream = match self.connect(&dst, rule, user, dns_policy, info.peer).await {
+                    Ok(dst_stream) => dst_stream,
+                    Err(err) => return reply_error(stream, protocol, err).await,
+                };
+                self.connect_request(stream, protocol, &dst, dst_stream, &early, session)
+                    .await?
             }
-            #[cfg(feature = "dns-lookup")]
-            addr_type::DOMAIN_NAME => {
-                let host = std::str::from_utf8(dst_ip).unwrap().trim();
-                let resolved_list = dns_lookup::lookup_host(host)?;
-                let resolved = resolved_list.first().unwrap();
-
-                format!("{resolved}:{dst_port}")
-                    .to_socket_addrs()
-                    .unwrap()
-                    .next()
-                    .unwrap()
+            #[cfg(feature = "bind")]
+            command::BIND => {
+                self.bind_request(stream, protocol, info, dst, user, dns_policy, session)
+                    .await?
             }
-            #[cfg(not(feature = "dns-lookup"))]
-            addr_type::DOMAIN_NAME => panic!("DOMAIN_NAME is not available"),
-            addr_type::IP_V6 => {
-                SocketAddr::from((TryInto::<[u8; 16]>::try_into(dst_ip).unwrap(), dst_port))
+            command::RESOLVE | command::RESOLVE_PTR
+                if self.tor_resolve && protocol == Protocol::Socks5 =>
+            {
+                self.resolve_request(stream, cmd, dst, dns_policy, session)
+                    .await?
             }
-            atyp => {
-                reply.rep = reply_opt::ADDRESS_TYPE_NOT_SUPPORTED;
-                stream.write_all(&reply.serialize()?).await?;
-                error!("ADDRESS TYPE NOT SUPPORTED ({atyp})")
+            cmd => {
+                let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+                reply(
+                    stream,
+                    protocol,
+                    reply_opt::COMMAND_NOT_SUPPORTED,
+                    unspecified,
+                )
+                .await?;
+                error!("Command {cmd} not available!")
             }
         };
 
-        stream.write_all(&reply.serialize()?).await?;
+        Ok(())
+    }
+
+    /// Dials the destination within the connect timeout, then sends it the
+    /// PROXY protocol header the matched rule asks for, if any
+    async fn connect(
+        &self,
+        dst: &Destination,
+        rule: Option<Rule>,
+        user: Option<&User>,
+        dns_policy: DnsPolicy,
+        client: Option<SocketAddr>,
+    ) -> io::Result<TcpStream> {
+        let proxy_protocol = rule.as_ref().and_then(|rule| rule.proxy_protocol);
+        let direct = self
+            .chain(rule.as_ref().map(|rule| &rule.action))?
+            .is_empty();
 
-        let mut dst_stream = TcpStream::connect(dst_socket).await?;
+        let started = time::Instant::now();
+        let dial = self.dial(dst, rule, user, dns_policy);
+        let mut dst_stream = match self.timeouts.connect {
+            Some(limit) => time::timeout(limit, dial).await.unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Connection to {dst} timed out after {limit:?}"),
+                ))
+            })?,
+            None => dial.await?,
+        };
+        self.metrics.connected(started.elapsed());
 
-        pipe(stream, &mut dst_stream).await;
+        if let Some(version) = proxy_protocol {
+            let destination = match dst {
+                Destination::Addr(addr) => *addr,
+                // through upstreams the peer is the first hop, not the destination
+                _ if direct => dst_stream.peer_addr()?,
+                _ => self.resolve(dst, dns_policy).await?,
+            };
+            let header = proxy_protocol::header(version, client, destination);
+            dst_stream.write_all(&header).await?;
+        }
 
-        Ok(())
+        Ok(dst_stream)
+    }
+
+    async fn connect_request<S: Stream>(
+        &self,
+        stream: &mut S,
+        protocol: Protocol,
+        dst: &Destination,
+        mut dst_stream: TcpStream,
+        early: &[u8],
+        session: &Session,
+    ) -> io::Result<()> {
+        let socket_addr = dst_stream.local_addr()?;
+        let resolved = dst_stream.peer_addr()?;
+
+        info!(local = %socket_addr, "Connected to {dst}");
+
+        reply(stream, protocol, reply_opt::SUCCEEDED, socket_addr).await?;
+        session.update(|state| {
+            state.resolved = Some(resolved);
+            state.local = Some(socket_addr);
+            state.reply = Some(reply_opt::SUCCEEDED);
+            state.phase = Phase::Relay;
+        });
+        dst_stream.write_all(early).await?;
+
+        relay::relay(
+            stream,
+            &mut dst_stream,
+            self.timeouts.idle,
+            &session.traffic,
+        )
+        .await
     }
 
     #[cfg(feature = "bind")]
-    async fn bind_request(&self, stream: &mut TcpStream) -> io::Result<()> {
+    #[allow(clippy::too_many_arguments)]
+    async fn bind_request<S: Stream>(
+        &self,
+        stream: &mut S,
+        protocol: Protocol,
+        info: &ConnectionInfo,
+        dst: Destination,
+        user: Option<&User>,
+        dns_policy: DnsPolicy,
+        session: &Session,
+    ) -> io::Result<()> {
         use rand::Rng;
 
-        let socket_addr = stream.local_addr()?;
-        let ip = socket_addr.ip();
-        let (atyp, bnd_addr) = ip_octs!(socket_addr);
+        let resolved = match self.resolve(&dst, dns_policy).await {
+            Ok(resolved) => resolved,
+            Err(err) => return reply_error(stream, protocol, err).await,
+        };
+        let expected = resolved.ip();
+
         let bnd_port = {
             let mut rng = rand::thread_rng();
             rng.gen()
         };
 
-        let bind_stream = TcpListener::bind((ip, bnd_port)).await?;
+        let affinity = Affinity {
+            user: user.map(|user| user.username.as_str()),
+            dst: &dst,
+        };
+        let bind_stream = match self
+            .egress(user)
+            .listen(self.bind_ip(info), bnd_port, affinity)
+        {
+            Ok(bind_stream) => bind_stream,
+            Err(err) => return reply_error(stream, protocol, err).await,
+        };
 
-        let reply = Reply::new(reply_opt::SUCCEEDED, atyp, &bnd_addr, bnd_port);
-        stream.write_all(&reply.serialize()?).await?;
+        let socket_addr = bind_stream.local_addr()?;
+        reply(stream, protocol, reply_opt::SUCCEEDED, socket_addr).await?;
+        session.update(|state| {
+            state.resolved = Some(resolved);
+            state.local = Some(socket_addr);
+            state.reply = Some(reply_opt::SUCCEEDED);
+        });
+
+        let mut socket = loop {
+            let (socket, addr) = bind_stream.accept().await?;
+            if expected.is_unspecified() || expected == addr.ip() {
+                info!(%addr, "Got a BIND connection");
+                break socket;
+            }
+            warn!(%addr, %expected, "Dropped an unexpected BIND connection");
+        };
+        session.update(|state| state.phase = Phase::Relay);
+
+        relay::relay(stream, &mut socket, self.timeouts.idle, &session.traffic).await
+    }
 
-        let (mut socket, addr) = bind_stream.accept().await?;
-        println!("Got a BIND connection from {addr:?}");
+    /// Replies to RESOLVE with the address of a name, following the DNS
+    /// policy, and to RESOLVE_PTR with the name of an address
+    async fn resolve_request<S: Stream>(
+        &self,
+        stream: &mut S,
+        cmd: u8,
+        dst: Destination,
+        dns_policy: DnsPolicy,
+        session: &Session,
+    ) -> io::Result<()> {
+        let resolved = match (cmd, dst) {
+            (command::RESOLVE, dst) => self
+                .resolve(&dst, dns_policy)
+                .await
+                .map(|addr| ip_octs!(addr)),
+            (_, Destination::Addr(addr)) => resolve::reverse(addr.ip())
+                .await
+                .map(|host| (addr_type::DOMAIN_NAME, host.into_bytes())),
+            (_, dst) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("RESOLVE_PTR needs an address ({dst})"),
+            )),
+        };
 
-        pipe(stream, &mut socket).await;
+        let (atyp, addr) = match resolved {
+            Ok(resolved) => resolved,
+            Err(err) => return reply_error(stream, Protocol::Socks5, err).await,
+        };
 
+        let reply = Reply::new(reply_opt::SUCCEEDED, atyp, &addr, 0);
+        stream.write_all(&reply.serialize()?).await?;
+        session.update(|state| state.reply = Some(reply_opt::SUCCEEDED));
         Ok(())
     }
+
+    /// Where BIND listens by default: on the address the client connected
+    /// to, or on the first listener address for sessions without one
+    #[cfg(feature = "bind")]
+    fn bind_ip(&self, info: &ConnectionInfo) -> std::net::IpAddr {
+        info.local
+            .or_else(|| self.addr.first().map(|listener| listener.addr))
+            .map_or(Ipv4Addr::UNSPECIFIED.into(), |addr| addr.ip())
+    }
+
+    /// Refuses domain names if the DNS policy says so
+    /// and applies the host overrides otherwise
+    fn destination(&self, dst: Destination, dns_policy: DnsPolicy) -> io::Result<Destination> {
+        match dst {
+            Destination::Domain(host, _) if dns_policy == DnsPolicy::Refuse => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("DOMAIN_NAME refused ({host})"),
+            )),
+            Destination::Domain(host, port) => match self.hosts.rewrite(&host) {
+                Target::Addr(ip) => Ok(Destination::Addr(SocketAddr::new(ip, port))),
+                Target::Name(host) => Ok(Destination::Domain(host, port)),
+            },
+            dst => Ok(dst),
+        }
+    }
+
+    /// Resolves the destination locally, following the DNS policy
+    async fn resolve(&self, dst: &Destination, dns_policy: DnsPolicy) -> io::Result<SocketAddr> {
+        match dst {
+            Destination::Addr(addr) => Ok(*addr),
+            Destination::Domain(host, port) => {
+                let started = time::Instant::now();
+                let resolved = resolve::lookup(host, *port, dns_policy).await;
+                self.metrics.resolved(started.elapsed());
+                resolved
+            }
+        }
+    }
+
+    /// Returns the upstream chain a connection with `action` goes through,
+    /// empty when it is made directly
+    fn chain(&self, action: Option<&Action>) -> io::Result<&[Upstream]> {
+        match action {
+            Some(Action::Upstream(name)) => self
+                .upstreams
+                .get(name)
+                .map(Vec::as_slice)
+                .ok_or_else(|| io::Error::other(format!("Unknown upstream {name}"))),
+            Some(Action::Direct) | Some(Action::Interface(_)) => Ok(&[]),
+            _ => Ok(&self.upstream),
+        }
+    }
+
+    /// Connects to the destination as the matched routing rule says, defaulting
+    /// to the upstream chain if there is one and to a direct connection otherwise.
+    /// Upstreams take care of resolving domain names themselves.
+    async fn dial(
+        &self,
+        dst: &Destination,
+        rule: Option<Rule>,
+        user: Option<&User>,
+        dns_policy: DnsPolicy,
+    ) -> io::Result<TcpStream> {
+        let action = rule.as_ref().map(|rule| &rule.action);
+        let chain = self.chain(action)?;
+
+        let mut egress = self.egress(user);
+        if let Some(Action::Interface(interface)) = action {
+            egress.interface = Some(interface.clone());
+        }
+        if let Some(mark) = rule.as_ref().and_then(|rule| rule.mark) {
+            egress.mark = Some(mark);
+        }
+
+        let affinity = Affinity {
+            user: user.map(|user| user.username.as_str()),
+            dst,
+        };
+
+        if !chain.is_empty() {
+            return upstream::connect(chain, dst, &egress, affinity).await;
+        }
+
+        egress
+            .connect(self.resolve(dst, dns_policy).await?, affinity)
+            .await
+    }
+
+    fn egress(&self, user: Option<&User>) -> Egress {
+        match user {
+            Some(user) => user.egress.or(&self.egress),
+            None => self.egress.clone(),
+        }
+    }
+
+    /// The DNS policy of the user, else of the listener, else of the server
+    fn dns_policy(&self, listener: Option<&Listener>, user: Option<&User>) -> DnsPolicy {
+        user.and_then(|user| user.dns_policy)
+            .or_else(|| listener.and_then(|listener| listener.dns_policy))
+            .unwrap_or(self.dns_policy)
+    }
 }
 
 impl Default for Server {
@@ -244,23 +1367,142 @@ impl Default for Server {
     }
 }
 
-async fn pipe(src: &mut TcpStream, dst: &mut TcpStream) -> ! {
-    let (mut src_read_half, mut src_write_half) = src.split();
-    let (mut dst_read_half, mut dst_write_half) = dst.split();
+/// Maps the error that ended a request to the reply sent back to the client
+fn reply_code(err: &io::Error) -> u8 {
+    if let Some(err) = err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<UpstreamError>())
+    {
+        return err.rep;
+    }
+
+    match err.kind() {
+        io::ErrorKind::Unsupported => reply_opt::ADDRESS_TYPE_NOT_SUPPORTED,
+        io::ErrorKind::NotFound => reply_opt::HOST_UNREACHABLE,
+        io::ErrorKind::HostUnreachable => reply_opt::HOST_UNREACHABLE,
+        io::ErrorKind::NetworkUnreachable => reply_opt::NETWORK_UNREACHABLE,
+        io::ErrorKind::ConnectionRefused => reply_opt::CONNECTION_REFUSED,
+        io::ErrorKind::PermissionDenied => reply_opt::CONNECTION_NOT_ALLOWED,
+        io::ErrorKind::TimedOut => reply_opt::TTL_EXPIRED,
+        _ => reply_opt::SOCKS_SERVER_FAILURE,
+    }
+}
+
+/// Sends a reply with the bound address, as the protocol wants it.
+/// SOCKS4 only tells success from failure, and knows no IPv6, while HTTP
+/// has a status and no address.
+async fn reply<S: Stream>(
+    stream: &mut S,
+    protocol: Protocol,
+    rep: u8,
+    addr: SocketAddr,
+) -> io::Result<()> {
+    let data = match protocol {
+        Protocol::Socks5 => {
+            let (atyp, ip) = ip_octs!(addr);
+            Reply::new(rep, atyp, &ip, addr.port()).serialize()?
+        }
+        Protocol::Socks4 => {
+            let cd = match rep {
+                reply_opt::SUCCEEDED => socks4::reply_code::GRANTED,
+                _ => socks4::reply_code::REJECTED,
+            };
+            let ip = match addr.ip() {
+                std::net::IpAddr::V4(ip) => ip.octets(),
+                std::net::IpAddr::V6(_) => [0; 4],
+            };
+            socks4::Reply::new(cd, ip, addr.port()).serialize()?
+        }
+        // the origin server answers forwarded requests itself
+        Protocol::HttpForward if rep == reply_opt::SUCCEEDED => return Ok(()),
+        Protocol::HttpConnect | Protocol::HttpForward => http::response(http::status(rep)),
+    };
+
+    stream.write_all(&data).await
+}
+
+/// Sends a failure reply for `err` and returns it
+async fn reply_error<S: Stream>(
+    stream: &mut S,
+    protocol: Protocol,
+    err: io::Error,
+) -> io::Result<()> {
+    let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    reply(stream, protocol, reply_code(&err), unspecified).await?;
+    Err(err)
+}
+
+/// Tells how a session ended
+fn session_ended(result: io::Result<()>) {
+    match result {
+        Ok(()) => debug!("Session closed"),
+        Err(err) => warn!(error = %err, "Session failed"),
+    }
+}
+
+/// Fails with `InvalidData` unless `buf` is at least `len` bytes long, the
+/// SOCKS5 parsers taking whole messages for granted. Clients closing right
+/// after connecting, as health checks do, send nothing at all.
+fn whole(buf: &[u8], len: Option<usize>, what: &str) -> io::Result<()> {
+    match len {
+        Some(len) if buf.len() >= len => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Truncated {what} of {} bytes", buf.len()),
+        )),
+    }
+}
+
+/// Length of a greeting: version, method count and methods
+fn greeting_len(buf: &[u8]) -> Option<usize> {
+    buf.get(1).map(|&nmethods| 2 + nmethods as usize)
+}
+
+/// Length of a username/password authentication
+fn auth_len(buf: &[u8]) -> Option<usize> {
+    let ulen = *buf.get(1)? as usize;
+    let plen = *buf.get(2 + ulen)? as usize;
+    Some(3 + ulen + plen)
+}
+
+/// Length of a request, up to its port. Unknown address types are left
+/// to the parser to report.
+fn request_len(buf: &[u8]) -> Option<usize> {
+    match *buf.get(3)? {
+        addr_type::IP_V4 => Some(10),
+        addr_type::IP_V6 => Some(22),
+        addr_type::DOMAIN_NAME => buf.get(4).map(|&len| 7 + len as usize),
+        _ => Some(4),
+    }
+}
 
+/// Names a request command, for logging
+fn command_name(cmd: u8) -> &'static str {
+    match cmd {
+        command::CONNECT => "CONNECT",
+        command::BIND => "BIND",
+        command::UDP_ASSOCIATE => "UDP ASSOCIATE",
+        command::RESOLVE => "RESOLVE",
+        command::RESOLVE_PTR => "RESOLVE_PTR",
+        _ => "unknown",
+    }
+}
+
+/// Reopens the access log file on every `SIGHUP`, until the server stops
+#[cfg(unix)]
+async fn reopen_on_hangup(
+    logger: Arc<AccessLogger>,
+    mut hangup: signal::unix::Signal,
+    handle: ServerHandle,
+) {
     loop {
         tokio::select! {
-            _ = async {
-                let mut buf = Vec::with_capacity(512);
-                src_read_half.read_buf(&mut buf).await.unwrap();
-                dst_write_half.write_all(&buf).await.unwrap();
-            } => { }
-
-            _ = async {
-                let mut buf = Vec::with_capacity(512);
-                dst_read_half.read_buf(&mut buf).await.unwrap();
-                src_write_half.write_all(&buf).await.unwrap();
-            } => { }
+            _ = hangup.recv() => {
+                if let Err(err) = logger.reopen() {
+                    warn!(error = %err, "Failed to reopen the access log");
+                }
+            }
+            _ = handle.stopped() => return,
         }
     }
 }
@@ -0,0 +1,150 @@
+//! # Listener
+//! The TCP addresses a server accepts clients on.
+//!
+//! Every listener shares the users, host overrides and routing table of the
+//! server, but may offer its own authentication methods and DNS policy and
+//! check its own rules before the server ones, e.g. to keep an admin-only
+//! port reachable from loopback alone. Behind a load balancer, a listener
+//! reads the client address from the PROXY protocol header the balancer
+//! sends.
+
+use crate::cidr::Cidr;
+use crate::resolve::DnsPolicy;
+use crate::route::{Routes, Rule};
+#[cfg(feature = "tls")]
+use crate::tls::Tls;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::net::SocketAddr;
+
+/// A TCP listener, written as `"0.0.0.0:1080"` or as an object
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Listener {
+    /// address to listen on
+    pub addr: SocketAddr,
+
+    /// authentication methods offered, the server ones when unset
+    pub auth: Option<Vec<u8>>,
+
+    /// routing rules checked before the server ones, shared by the clones
+    /// of the listener so they can be replaced at runtime
+    pub rules: Routes,
+
+    /// load balancers whose PROXY protocol headers are trusted
+    pub proxy_protocol: Vec<Cidr>,
+
+    /// DNS policy of the sessions without a user one, the server one when unset
+    pub dns_policy: Option<DnsPolicy>,
+
+    /// TLS settings, the SOCKS handshake then happening inside TLS
+    #[cfg(feature = "tls")]
+    pub tls: Option<Tls>,
+}
+
+impl Listener {
+    /// Creates a listener with the server settings
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            auth: None,
+            rules: Routes::new(),
+            proxy_protocol: vec![],
+            dns_policy: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Sets the authentication methods offered on this listener
+    pub fn with_auth(mut self, auth: Vec<u8>) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Sets the rules checked before the server ones on this listener
+    pub fn with_rules(mut self, rules: Vec<Rule>) -> Self {
+        self.rules = Routes::from(rules);
+        self
+    }
+
+    /// Reads the PROXY protocol header of connections from `balancers`
+    pub fn with_proxy_protocol(mut self, balancers: Vec<Cidr>) -> Self {
+        self.proxy_protocol = balancers;
+        self
+    }
+
+    /// Sets the DNS policy of the sessions on this listener
+    pub fn with_dns_policy(mut self, dns_policy: DnsPolicy) -> Self {
+        self.dns_policy = Some(dns_policy);
+        self
+    }
+
+    /// Terminates TLS on this listener
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: Tls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+}
+
+impl From<SocketAddr> for Listener {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr)
+    }
+}
+
+impl<'de> Deserialize<'de> for Listener {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Full {
+            addr: SocketAddr,
+            #[serde(default)]
+            auth: Option<Vec<u8>>,
+            #[serde(default)]
+            rules: Vec<Rule>,
+            #[serde(default)]
+            proxy_protocol: Vec<Cidr>,
+            #[serde(default)]
+            dns_policy: Option<DnsPolicy>,
+            #[cfg(feature = "tls")]
+            #[serde(default)]
+            tls: Option<Tls>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Addr(SocketAddr),
+            Full(Full),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Addr(addr) => Self::new(addr),
+            Repr::Full(full) => Self {
+                addr: full.addr,
+                auth: full.auth,
+                rules: Routes::from(full.rules),
+                proxy_protocol: full.proxy_protocol,
+                dns_policy: full.dns_policy,
+                #[cfg(feature = "tls")]
+                tls: full.tls,
+            },
+        })
+    }
+}
+
+/// Reads a single listener or a list of them
+pub(crate) fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Listener>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        One(Listener),
+        Many(Vec<Listener>),
+    }
+
+    Ok(match Repr::deserialize(deserializer)? {
+        Repr::One(listener) => vec![listener],
+        Repr::Many(listeners) => listeners,
+    })
+}
@@ -0,0 +1,426 @@
+//! # Metrics
+//! Counters describing what the server has been doing, in the Prometheus
+//! text format, optionally served over HTTP at `/metrics`.
+//!
+//! Requests, bytes and authentications can be labelled with the user, at
+//! the cost of one series per user. Failed authentications are labelled
+//! with the username attempted, known or not.
+//!
+//! UDP ASSOCIATE is not supported, hence no gauge of UDP associations.
+
+use crate::connection::Stream;
+use crate::handle::ServerHandle;
+use crate::http;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::time;
+use tracing::warn;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Time a scraper gets to send its request
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where metrics are served
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Prometheus {
+    /// address of the HTTP endpoint
+    pub addr: SocketAddr,
+
+    /// label requests, bytes and authentications with the user
+    #[serde(default)]
+    pub per_user: bool,
+}
+
+impl Prometheus {
+    /// Serves metrics at `addr`, without user labels
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            per_user: false,
+        }
+    }
+
+    /// Labels requests, bytes and authentications with the user
+    pub fn with_per_user(mut self, per_user: bool) -> Self {
+        self.per_user = per_user;
+        self
+    }
+}
+
+type Counters<K> = Mutex<BTreeMap<K, u64>>;
+
+/// Server counters, shared by every session
+#[derive(Debug, Default)]
+pub struct Metrics {
+    sessions: AtomicU64,
+    active: AtomicU64,
+    rejected: Counters<&'static str>,
+    handshake_failures: Counters<&'static str>,
+    auth_successes: Counters<String>,
+    auth_failures: Counters<String>,
+    requests: Counters<(&'static str, u8, String)>,
+    bytes_up: Counters<String>,
+    bytes_down: Counters<String>,
+    connect_latency: Histogram,
+    dns_latency: Histogram,
+}
+
+/// Latencies, counted in the buckets they fit in
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    /// Sessions started, counting those still running
+    pub fn sessions(&self) -> u64 {
+        self.sessions.load(Ordering::Relaxed)
+    }
+
+    /// Sessions still running
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Sessions closed for not completing the handshake in time
+    pub fn handshake_timeouts(&self) -> u64 {
+        self.handshake_failures
+            .lock()
+            .unwrap()
+            .get("timeout")
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Clients rejected for `reason`: `auth` for wrong credentials, `method`
+    /// for no acceptable authentication method and `rule` for routing rules
+    pub fn rejected(&self, reason: &str) -> u64 {
+        self.rejected
+            .lock()
+            .unwrap()
+            .get(reason)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Renders every metric in the Prometheus text format, with user labels
+    /// or summed over users
+    pub fn render(&self, per_user: bool) -> String {
+        let user = |user: &str| {
+            if per_user {
+                format!(",user=\"{}\"", escape(user))
+            } else {
+                String::new()
+            }
+        };
+        let mut out = String::new();
+
+        family(
+            &mut out,
+            "connections_accepted_total",
+            "counter",
+            "Connections accepted",
+        );
+        sample(&mut out, "connections_accepted_total", "", self.sessions());
+
+        family(&mut out, "connections_active", "gauge", "Sessions running");
+        sample(&mut out, "connections_active", "", self.active());
+
+        family(
+            &mut out,
+            "connections_rejected_total",
+            "counter",
+            "Clients rejected, by reason",
+        );
+        for (reason, count) in self.rejected.lock().unwrap().iter() {
+            sample(
+                &mut out,
+                "connections_rejected_total",
+                &format!("reason=\"{reason}\""),
+                *count,
+            );
+        }
+
+        family(
+            &mut out,
+            "handshake_failures_total",
+            "counter",
+            "Failed handshakes, by type",
+        );
+        for (kind, count) in self.handshake_failures.lock().unwrap().iter() {
+            sample(
+                &mut out,
+                "handshake_failures_total",
+                &format!("type=\"{kind}\""),
+                *count,
+            );
+        }
+
+        family(
+            &mut out,
+            "auth_total",
+            "counter",
+            "Authentications, by result",
+        );
+        let successes = sum(self
+            .auth_successes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, count)| (format!("result=\"success\"{}", user(name)), *count)));
+        for (labels, count) in successes {
+            sample(&mut out, "auth_total", &labels, count);
+        }
+        let failures = sum(self
+            .auth_failures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, count)| (format!("result=\"failure\"{}", user(name)), *count)));
+        for (labels, count) in failures {
+            sample(&mut out, "auth_total", &labels, count);
+        }
+
+        family(
+            &mut out,
+            "requests_total",
+            "counter",
+            "Requests, by command and reply code",
+        );
+        let requests =
+            sum(self
+                .requests
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|((command, reply, name), count)| {
+                    (
+                        format!("command=\"{command}\",reply=\"{reply}\"{}", user(name)),
+                        *count,
+                    )
+                }));
+        for (labels, count) in requests {
+            sample(&mut out, "requests_total", &labels, count);
+        }
+
+        family(
+            &mut out,
+            "relayed_bytes_total",
+            "counter",
+            "Bytes relayed by finished sessions, by direction",
+        );
+        for (direction, bytes) in [("up", &self.bytes_up), ("down", &self.bytes_down)] {
+            let bytes =
+                sum(bytes.lock().unwrap().iter().map(|(name, count)| {
+                    (format!("direction=\"{direction}\"{}", user(name)), *count)
+                }));
+            for (labels, count) in bytes {
+                sample(&mut out, "relayed_bytes_total", &labels, count);
+            }
+        }
+
+        self.connect_latency.render(
+            &mut out,
+            "connect_duration_seconds",
+            "Time taken to connect to destinations",
+        );
+        self.dns_latency.render(
+            &mut out,
+            "dns_duration_seconds",
+            "Time taken to resolve domain names",
+        );
+
+        out
+    }
+
+    /// Counts a new session, returning its id and what keeps it counted as
+    /// active until dropped
+    pub(crate) fn session_started(self: &Arc<Self>) -> (u64, Active) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let id = self.sessions.fetch_add(1, Ordering::Relaxed) + 1;
+        (id, Active(Arc::clone(self)))
+    }
+
+    /// Counts a finished session, and the request it served if it got there
+    pub(crate) fn session_ended(
+        &self,
+        user: Option<&str>,
+        request: Option<(&'static str, u8)>,
+        up: u64,
+        down: u64,
+    ) {
+        let user = user.unwrap_or_default().to_string();
+        if let Some((command, reply)) = request {
+            add(&self.requests, (command, reply, user.clone()), 1);
+        }
+        add(&self.bytes_up, user.clone(), up);
+        add(&self.bytes_down, user, down);
+    }
+
+    pub(crate) fn rejected_for(&self, reason: &'static str) {
+        add(&self.rejected, reason, 1);
+    }
+
+    pub(crate) fn handshake_failed(&self, kind: &'static str) {
+        add(&self.handshake_failures, kind, 1);
+    }
+
+    pub(crate) fn authenticated(&self, user: &str) {
+        add(&self.auth_successes, user.to_string(), 1);
+    }
+
+    /// Counts a failed authentication, by the username attempted
+    pub(crate) fn auth_failed(&self, user: &str) {
+        add(&self.auth_failures, user.to_string(), 1);
+    }
+
+    pub(crate) fn connected(&self, elapsed: Duration) {
+        self.connect_latency.observe(elapsed);
+    }
+
+    pub(crate) fn resolved(&self, elapsed: Duration) {
+        self.dns_latency.observe(elapsed);
+    }
+}
+
+/// A session counted as active, for as long as its task holds this, so
+/// that sessions dropped on shutdown are not counted forever
+#[derive(Debug)]
+pub(crate) struct Active(Arc<Metrics>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        family(out, name, "histogram", help);
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            let labels = format!("le=\"{bound}\"");
+            sample(
+                out,
+                &format!("{name}_bucket"),
+                &labels,
+                bucket.load(Ordering::Relaxed),
+            );
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        sample(out, &format!("{name}_bucket"), "le=\"+Inf\"", count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "proksi_{name}_sum {sum}");
+        sample(out, &format!("{name}_count"), "", count);
+    }
+}
+
+/// Answers `GET /metrics` on `listener` until the server stops
+pub(crate) async fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    per_user: bool,
+    handle: ServerHandle,
+) {
+    loop {
+        let mut stream = tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!(error = %err, "Failed to accept a metrics scrape");
+                    time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = handle.stopped() => return,
+        };
+
+        let metrics = Arc::clone(&metrics);
+        handle.spawn(async move {
+            if let Err(err) = scrape(&mut stream, &metrics, per_user).await {
+                warn!(error = %err, "Failed to serve a metrics scrape");
+            }
+        });
+    }
+}
+
+async fn scrape<S: Stream>(
+    stream: &mut S,
+    metrics: &Metrics,
+    per_user: bool,
+) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(512);
+    let len = time::timeout(SCRAPE_TIMEOUT, http::read_head(stream, &mut buf))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    let head = http::RequestHead::parse(&buf[..len])?;
+
+    let response = match (head.method.as_str(), head.target.as_str()) {
+        ("GET", "/metrics") => http::response_with_body(
+            200,
+            "text/plain; version=0.0.4",
+            metrics.render(per_user).as_bytes(),
+        ),
+        (_, "/metrics") => http::response(405),
+        _ => http::response(404),
+    };
+    stream.write_all(&response).await
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP proksi_{name} {help}");
+    let _ = writeln!(out, "# TYPE proksi_{name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: u64) {
+    let _ = match labels {
+        "" => writeln!(out, "proksi_{name} {value}"),
+        _ => writeln!(out, "proksi_{name}{{{labels}}} {value}"),
+    };
+}
+
+/// Sums the samples ending up with the same labels
+fn sum(samples: impl Iterator<Item = (String, u64)>) -> BTreeMap<String, u64> {
+    let mut sums = BTreeMap::new();
+    for (labels, count) in samples {
+        *sums.entry(labels).or_default() += count;
+    }
+    sums
+}
+
+fn add<K: Ord>(counters: &Counters<K>, key: K, n: u64) {
+    *counters.lock().unwrap().entry(key).or_default() += n;
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
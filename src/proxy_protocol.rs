@@ -0,0 +1,197 @@
+//! # PROXY protocol
+//! Headers load balancers put in front of a connection to tell the address
+//! of the client behind them, in the text form of version 1 or the binary
+//! form of version 2 of the
+//! [`PROXY protocol`](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt).
+//!
+//! Listeners only read them from the balancers they trust, anyone else
+//! being able to claim any address otherwise. Routing rules may in turn
+//! have one sent to destinations, for them to know the client of proksi.
+
+use crate::connection::Stream;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::AsyncReadExt;
+
+/// Signature starting every version 2 header
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest version 1 header, its line ending included
+const MAX_V1: usize = 107;
+
+/// Version of the PROXY protocol headers sent to destinations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyVersion {
+    /// the text header
+    V1,
+
+    /// the binary header
+    V2,
+}
+
+/// Addresses of a connection proxied by a load balancer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedAddrs {
+    /// the client
+    pub source: SocketAddr,
+
+    /// the address the client connected to, on the balancer
+    pub destination: SocketAddr,
+}
+
+/// Reads a version 1 or 2 header, and nothing past it. Headers of health
+/// checks and of unknown protocols carry no addresses.
+pub(crate) async fn read_header<S: Stream>(stream: &mut S) -> io::Result<Option<ProxiedAddrs>> {
+    match stream.read_u8().await? {
+        b'P' => read_v1(stream).await,
+        b'\r' => read_v2(stream).await,
+        byte => Err(invalid(format!("No PROXY protocol header ({byte:#x})"))),
+    }
+}
+
+async fn read_v1<S: Stream>(stream: &mut S) -> io::Result<Option<ProxiedAddrs>> {
+    let mut line = vec![b'P'];
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= MAX_V1 {
+            return Err(invalid("PROXY protocol header too long".to_string()));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("Invalid PROXY protocol header".to_string()))?;
+    let fields: Vec<_> = line.split(' ').collect();
+
+    match fields[..] {
+        ["PROXY", "TCP4" | "TCP6", source, destination, source_port, destination_port] => {
+            let addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+                let ip: IpAddr = ip
+                    .parse()
+                    .map_err(|_| invalid(format!("Invalid address {ip}")))?;
+                let port = port
+                    .parse()
+                    .map_err(|_| invalid(format!("Invalid port {port}")))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+
+            Ok(Some(ProxiedAddrs {
+                source: addr(source, source_port)?,
+                destination: addr(destination, destination_port)?,
+            }))
+        }
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        _ => Err(invalid(format!("Invalid PROXY protocol header {line:?}"))),
+    }
+}
+
+async fn read_v2<S: Stream>(stream: &mut S) -> io::Result<Option<ProxiedAddrs>> {
+    let mut header = [0; 16];
+    header[0] = b'\r';
+    stream.read_exact(&mut header[1..]).await?;
+
+    if header[..12] != SIGNATURE || header[12] >> 4 != 2 {
+        return Err(invalid("Invalid PROXY protocol v2 header".to_string()));
+    }
+
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+
+    // LOCAL connections come from the balancer itself
+    if header[12] & 0xf == 0 {
+        return Ok(None);
+    }
+
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    let addrs = match header[13] >> 4 {
+        0x1 if len >= 12 => {
+            let ip = |at: usize| Ipv4Addr::from(<[u8; 4]>::try_from(&body[at..at + 4]).unwrap());
+            ProxiedAddrs {
+                source: SocketAddr::new(ip(0).into(), port(8)),
+                destination: SocketAddr::new(ip(4).into(), port(10)),
+            }
+        }
+        0x2 if len >= 36 => {
+            let ip = |at: usize| Ipv6Addr::from(<[u8; 16]>::try_from(&body[at..at + 16]).unwrap());
+            ProxiedAddrs {
+                source: SocketAddr::new(ip(0).into(), port(32)),
+                destination: SocketAddr::new(ip(16).into(), port(34)),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(addrs))
+}
+
+/// Writes a header telling of a connection from `source` to `destination`,
+/// or of an unknown one when the client has no IP address
+pub(crate) fn header(
+    version: ProxyVersion,
+    source: Option<SocketAddr>,
+    destination: SocketAddr,
+) -> Vec<u8> {
+    let Some(source) = source else {
+        return match version {
+            ProxyVersion::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+            ProxyVersion::V2 => [&SIGNATURE[..], &[0x21, 0x00, 0, 0]].concat(),
+        };
+    };
+
+    // both addresses are of the same family, IPv4 ones being mapped if not
+    let (source, destination) = match (source, destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+            (source, destination)
+        }
+        _ => (mapped(source), mapped(destination)),
+    };
+
+    match version {
+        ProxyVersion::V1 => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {family} {} {} {} {}\r\n",
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyVersion::V2 => {
+            let mut header = SIGNATURE.to_vec();
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                    header.extend([0x21, 0x11, 0, 12]);
+                    header.extend(source.octets());
+                    header.extend(destination.octets());
+                }
+                (source, destination) => {
+                    header.extend([0x21, 0x21, 0, 36]);
+                    header.extend(ipv6(source).octets());
+                    header.extend(ipv6(destination).octets());
+                }
+            }
+            header.extend(source.port().to_be_bytes());
+            header.extend(destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn mapped(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(ipv6(addr.ip()).into(), addr.port())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
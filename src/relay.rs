@@ -0,0 +1,149 @@
+//! # Relay
+//! Copying bytes both ways between the client and the destination,
+//! giving up once neither side has sent anything for a while.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Instant};
+
+/// Bytes relayed in each direction
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    /// from the client to the destination
+    pub up: AtomicU64,
+
+    /// from the destination to the client
+    pub down: AtomicU64,
+}
+
+impl Traffic {
+    /// Bytes sent by the client so far
+    pub fn up(&self) -> u64 {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent to the client so far
+    pub fn down(&self) -> u64 {
+        self.down.load(Ordering::Relaxed)
+    }
+}
+
+/// When data last went through, in milliseconds since `start`
+struct Activity {
+    start: Instant,
+    last: AtomicU64,
+}
+
+/// A stream recording the bytes read from it and when
+struct Tracked<'a, S> {
+    inner: &'a mut S,
+    activity: &'a Activity,
+    read: &'a AtomicU64,
+}
+
+/// Relays `client` and `remote` until both are done or the relay has been
+/// idle for `idle`, which fails with [`io::ErrorKind::TimedOut`]
+pub(crate) async fn relay<A, B>(
+    client: &mut A,
+    remote: &mut B,
+    idle: Option<Duration>,
+    traffic: &Traffic,
+) -> io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let activity = Activity {
+        start: Instant::now(),
+        last: AtomicU64::new(0),
+    };
+
+    let mut client = Tracked {
+        inner: client,
+        activity: &activity,
+        read: &traffic.up,
+    };
+    let mut remote = Tracked {
+        inner: remote,
+        activity: &activity,
+        read: &traffic.down,
+    };
+
+    let copy = io::copy_bidirectional(&mut client, &mut remote);
+
+    let Some(idle) = idle else {
+        return copy.await.map(|_| ());
+    };
+
+    tokio::select! {
+        res = copy => res.map(|_| ()),
+        _ = activity.idle(idle) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Relay idle for {idle:?}"),
+        )),
+    }
+}
+
+impl Activity {
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+
+    /// Resolves once nothing went through for `idle`
+    async fn idle(&self, idle: Duration) {
+        loop {
+            let deadline = self.last() + idle;
+            if Instant::now() >= deadline {
+                return;
+            }
+            time::sleep_until(deadline).await;
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+
+        ready!(Pin::new(&mut *this.inner).poll_read(cx, buf))?;
+
+        let read = (buf.filled().len() - before) as u64;
+        if read > 0 {
+            this.read.fetch_add(read, Ordering::Relaxed);
+            this.activity.touch();
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,101 @@
+//! # Resolve
+//! Runtime policy deciding how `DOMAIN_NAME` destinations are resolved.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+/// How domain names requested by clients are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsPolicy {
+    /// resolve locally and use the first address returned
+    #[default]
+    Local,
+
+    /// refuse domain names with `ADDRESS_TYPE_NOT_SUPPORTED`
+    Refuse,
+
+    /// only use IPv4 addresses
+    Ipv4Only,
+
+    /// only use IPv6 addresses
+    Ipv6Only,
+
+    /// use an IPv4 address if there is one, any address otherwise
+    PreferV4,
+
+    /// use an IPv6 address if there is one, any address otherwise
+    PreferV6,
+}
+
+impl DnsPolicy {
+    /// Picks one of the resolved addresses according to the policy
+    pub fn pick(self, addrs: &[IpAddr]) -> Option<IpAddr> {
+        let v4 = || addrs.iter().find(|ip| ip.is_ipv4());
+        let v6 = || addrs.iter().find(|ip| ip.is_ipv6());
+
+        match self {
+            Self::Local => addrs.first(),
+            Self::Refuse => None,
+            Self::Ipv4Only => v4(),
+            Self::Ipv6Only => v6(),
+            Self::PreferV4 => v4().or_else(|| addrs.first()),
+            Self::PreferV6 => v6().or_else(|| addrs.first()),
+        }
+        .copied()
+    }
+}
+
+/// Resolves `host` following `policy`.
+///
+/// A refused name fails with [`io::ErrorKind::Unsupported`] and a name without
+/// a suitable address with [`io::ErrorKind::NotFound`].
+pub(crate) async fn lookup(host: &str, port: u16, policy: DnsPolicy) -> io::Result<SocketAddr> {
+    if policy == DnsPolicy::Refuse {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("DOMAIN_NAME refused ({host})"),
+        ));
+    }
+
+    let addrs = lookup_host(host).await?;
+
+    policy
+        .pick(&addrs)
+        .map(|ip| SocketAddr::new(ip, port))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No suitable address for {host} ({policy:?})"),
+            )
+        })
+}
+
+#[cfg(feature = "dns-lookup")]
+async fn lookup_host(host: &str) -> io::Result<Vec<IpAddr>> {
+    let host = host.to_string();
+    tokio::task::spawn_blocking(move || dns_lookup::lookup_host(&host)).await?
+}
+
+#[cfg(not(feature = "dns-lookup"))]
+async fn lookup_host(host: &str) -> io::Result<Vec<IpAddr>> {
+    Ok(tokio::net::lookup_host((host, 0))
+        .await?
+        .map(|addr| addr.ip())
+        .collect())
+}
+
+/// Looks up the name of `ip`, which is its textual form when it has none
+#[cfg(feature = "dns-lookup")]
+pub(crate) async fn reverse(ip: IpAddr) -> io::Result<String> {
+    tokio::task::spawn_blocking(move || dns_lookup::lookup_addr(&ip)).await?
+}
+
+#[cfg(not(feature = "dns-lookup"))]
+pub(crate) async fn reverse(ip: IpAddr) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("No reverse lookup of {ip} without the dns-lookup feature"),
+    ))
+}
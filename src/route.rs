@@ -0,0 +1,307 @@
+//! # Route
+//! Rule-based selection of the outbound used for each request.
+//!
+//! Rules are evaluated in order and the first one matching the request wins.
+//! Every matcher left empty matches anything; a rule with several matchers
+//! only matches when all of them do. Requests matching no rule use the
+//! server defaults (the upstream chain if there is one, direct otherwise).
+//!
+//! Rules see the destination as requested by the client, before the host
+//! overrides are applied, so `cidr` only matches IP address requests.
+//!
+//! The interface and mark rules set only apply to outbound connections:
+//! BIND listens from the user or server egress alone.
+
+use crate::cidr::Cidr;
+use crate::destination::Destination;
+use crate::proxy_protocol::ProxyVersion;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+/// What to do with a request
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// connect directly, ignoring any default upstream
+    Direct,
+
+    /// connect through the named upstream chain
+    Upstream(String),
+
+    /// connect directly from the named network interface
+    Interface(String),
+
+    /// refuse the request with `CONNECTION_NOT_ALLOWED`
+    Reject,
+}
+
+/// An inclusive range of ports, written as `443` or `"8000-9000"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    /// first port of the range
+    pub start: u16,
+
+    /// last port of the range
+    pub end: u16,
+}
+
+/// A routing rule
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Rule {
+    /// optional name, for reference in logs and dry runs
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// exact destination domain names
+    #[serde(default)]
+    pub domain: Vec<String>,
+
+    /// destination domain suffixes, `example.com` matching itself and any subdomain
+    #[serde(default)]
+    pub domain_suffix: Vec<String>,
+
+    /// destination address blocks
+    #[serde(default)]
+    pub cidr: Vec<Cidr>,
+
+    /// destination ports
+    #[serde(default)]
+    pub port: Vec<PortRange>,
+
+    /// authenticated usernames
+    #[serde(default)]
+    pub user: Vec<String>,
+
+    /// client address blocks
+    #[serde(default)]
+    pub client: Vec<Cidr>,
+
+    /// user ids of Unix socket clients
+    #[serde(default)]
+    pub uid: Vec<u32>,
+
+    /// what to do with matching requests
+    pub action: Action,
+
+    /// firewall mark set on outbound sockets of matching requests (Linux only)
+    #[serde(default)]
+    pub mark: Option<u32>,
+
+    /// PROXY protocol header telling destinations of matching requests
+    /// about the client. Names reached through upstreams are resolved
+    /// locally for the header.
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyVersion>,
+}
+
+/// The request details rules are matched against
+#[derive(Debug, Clone, Copy)]
+pub struct RouteQuery<'a> {
+    /// requested destination
+    pub dst: &'a Destination,
+
+    /// authenticated username, if any
+    pub user: Option<&'a str>,
+
+    /// client address, if known
+    pub client: Option<IpAddr>,
+
+    /// client user id, over Unix sockets
+    pub uid: Option<u32>,
+}
+
+/// The rule a request matched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteMatch {
+    /// position of the rule in the table
+    pub index: usize,
+
+    /// the rule itself
+    pub rule: Rule,
+}
+
+/// Shared, reloadable routing table.
+///
+/// Cloning a `Routes` gives another handle to the same table.
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    inner: Arc<RwLock<Vec<Rule>>>,
+}
+
+impl PortRange {
+    /// Tells if `port` is in the range
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl Rule {
+    /// Creates a rule matching everything
+    pub fn new(action: Action) -> Self {
+        Self {
+            name: None,
+            domain: vec![],
+            domain_suffix: vec![],
+            cidr: vec![],
+            port: vec![],
+            user: vec![],
+            client: vec![],
+            uid: vec![],
+            action,
+            mark: None,
+            proxy_protocol: None,
+        }
+    }
+
+    /// Tells if the rule matches `query`
+    pub fn matches(&self, query: &RouteQuery) -> bool {
+        let host = match query.dst {
+            Destination::Domain(host, _) => Some(host.trim_end_matches('.').to_ascii_lowercase()),
+            Destination::Addr(_) => None,
+        };
+        let ip = match query.dst {
+            Destination::Addr(addr) => Some(addr.ip()),
+            Destination::Domain(..) => None,
+        };
+
+        let domain = self.domain.is_empty()
+            || host
+                .as_ref()
+                .is_some_and(|host| self.domain.iter().any(|d| d.eq_ignore_ascii_case(host)));
+        let domain_suffix = self.domain_suffix.is_empty()
+            || host.as_ref().is_some_and(|host| {
+                self.domain_suffix.iter().any(|suffix| {
+                    let suffix = suffix.trim_start_matches('.').to_ascii_lowercase();
+                    host == &suffix || host.ends_with(&format!(".{suffix}"))
+                })
+            });
+        let cidr = self.cidr.is_empty()
+            || ip.is_some_and(|ip| self.cidr.iter().any(|cidr| cidr.contains(ip)));
+        let port = self.port.is_empty()
+            || self
+                .port
+                .iter()
+                .any(|range| range.contains(query.dst.port()));
+        let user = self.user.is_empty()
+            || query
+                .user
+                .is_some_and(|user| self.user.iter().any(|u| u == user));
+        let client = self.client.is_empty()
+            || query
+                .client
+                .is_some_and(|ip| self.client.iter().any(|cidr| cidr.contains(ip)));
+        let uid = self.uid.is_empty() || query.uid.is_some_and(|uid| self.uid.contains(&uid));
+
+        domain && domain_suffix && cidr && port && user && client && uid
+    }
+}
+
+impl Routes {
+    /// Creates an empty table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces all the rules
+    pub fn replace(&self, rules: Vec<Rule>) {
+        *self.inner.write().unwrap() = rules;
+    }
+
+    /// Returns a copy of the rules
+    pub fn rules(&self) -> Vec<Rule> {
+        self.inner.read().unwrap().clone()
+    }
+
+    /// Returns how many rules there are
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().len()
+    }
+
+    /// Tells if there are no rules
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Finds the first rule matching `query`, without acting on it
+    pub fn evaluate(&self, query: &RouteQuery) -> Option<RouteMatch> {
+        self.inner
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(query))
+            .map(|(index, rule)| RouteMatch {
+                index,
+                rule: rule.clone(),
+            })
+    }
+}
+
+impl PartialEq for Routes {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner) || self.rules() == other.rules()
+    }
+}
+
+impl Eq for Routes {}
+
+impl From<Vec<Rule>> for Routes {
+    fn from(rules: Vec<Rule>) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(rules)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Routes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl Serialize for Routes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.rules().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Port(u16),
+            Range(String),
+        }
+
+        let (start, end) = match Repr::deserialize(deserializer)? {
+            Repr::Port(port) => (port, port),
+            Repr::Range(range) => {
+                let parse = |port: &str| port.trim().parse::<u16>().map_err(de::Error::custom);
+                match range.split_once('-') {
+                    Some((start, end)) => (parse(start)?, parse(end)?),
+                    None => (parse(&range)?, parse(&range)?),
+                }
+            }
+        };
+
+        if start > end {
+            return Err(de::Error::custom(format!(
+                "Invalid port range {start}-{end}"
+            )));
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.start == self.end {
+            serializer.serialize_u16(self.start)
+        } else {
+            serializer.collect_str(&format_args!("{}-{}", self.start, self.end))
+        }
+    }
+}
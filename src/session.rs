@@ -0,0 +1,211 @@
+//! # Session
+//! What is known of a client session as it goes: who the client is, what
+//! it asked for, how it was answered and how much went through.
+//!
+//! Running sessions are kept in a registry, for [`ServerHandle`](crate::handle::ServerHandle)
+//! to list them and to close them on demand.
+
+use crate::relay::Traffic;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+/// How far a session has got
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// greeting and authenticating the client
+    #[default]
+    Handshake,
+
+    /// serving the request, as connecting to the destination
+    Request,
+
+    /// relaying data between the client and the destination
+    Relay,
+}
+
+/// A running session, as seen from outside
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// unique among the sessions of a server
+    pub id: u64,
+
+    /// client address, the one behind a load balancer if told
+    pub peer: Option<SocketAddr>,
+
+    /// authenticated user
+    pub user: Option<String>,
+
+    /// requested command
+    pub command: Option<&'static str>,
+
+    /// destination as requested
+    pub destination: Option<String>,
+
+    /// when the connection was accepted
+    pub started: SystemTime,
+
+    /// bytes sent by the client so far
+    pub up: u64,
+
+    /// bytes sent to the client so far
+    pub down: u64,
+
+    /// how far the session has got
+    pub phase: Phase,
+}
+
+/// A client session, filled in as it learns about the client and its request
+#[derive(Debug)]
+pub(crate) struct Session {
+    /// unique among the sessions of a server
+    pub id: u64,
+
+    /// when the connection was accepted
+    pub started: SystemTime,
+
+    /// bytes relayed so far
+    pub traffic: Traffic,
+
+    start: Instant,
+    state: Mutex<State>,
+    kill: CancellationToken,
+}
+
+/// The parts of a session known only once it gets there
+#[derive(Debug, Clone, Default)]
+pub(crate) struct State {
+    /// client address, the one behind a load balancer if told
+    pub peer: Option<SocketAddr>,
+
+    /// authenticated user
+    pub user: Option<String>,
+
+    /// requested command
+    pub command: Option<&'static str>,
+
+    /// destination as requested
+    pub destination: Option<String>,
+
+    /// address actually connected to or bound for
+    pub resolved: Option<SocketAddr>,
+
+    /// egress address connected from or listened on
+    pub local: Option<SocketAddr>,
+
+    /// SOCKS5 reply code the request was answered with
+    pub reply: Option<u8>,
+
+    /// how far the session has got
+    pub phase: Phase,
+}
+
+/// The running sessions of a server, by id
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Registry(Arc<Mutex<HashMap<u64, Arc<Session>>>>);
+
+impl Session {
+    /// Starts a session of a client at `peer`
+    pub fn new(id: u64, peer: Option<SocketAddr>) -> Self {
+        Self {
+            id,
+            started: SystemTime::now(),
+            traffic: Traffic::default(),
+            start: Instant::now(),
+            state: Mutex::new(State {
+                peer,
+                ..State::default()
+            }),
+            kill: CancellationToken::new(),
+        }
+    }
+
+    /// Records something learnt about the session
+    pub fn update(&self, update: impl FnOnce(&mut State)) {
+        update(&mut self.state.lock().unwrap())
+    }
+
+    /// Returns what is known of the session so far
+    pub fn state(&self) -> State {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Returns how long the session has been running
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Runs `future` until it is done or the session is killed, which
+    /// fails with [`io::ErrorKind::ConnectionAborted`]
+    pub async fn run<T>(&self, future: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+        tokio::select! {
+            result = future => result,
+            _ = self.kill.cancelled() => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "Session terminated",
+            )),
+        }
+    }
+
+    fn info(&self) -> SessionInfo {
+        let state = self.state();
+        SessionInfo {
+            id: self.id,
+            peer: state.peer,
+            user: state.user,
+            command: state.command,
+            destination: state.destination,
+            started: self.started,
+            up: self.traffic.up(),
+            down: self.traffic.down(),
+            phase: state.phase,
+        }
+    }
+}
+
+impl Registry {
+    pub fn insert(&self, session: Arc<Session>) {
+        self.0.lock().unwrap().insert(session.id, session);
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.0.lock().unwrap().remove(&id);
+    }
+
+    /// Lists the running sessions, oldest first
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<_> = self.0.lock().unwrap().values().map(|s| s.info()).collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    /// Kills the session with this id, telling if there was one
+    pub fn kill(&self, id: u64) -> bool {
+        match self.0.lock().unwrap().get(&id) {
+            Some(session) => {
+                session.kill.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Kills every session of `user`, returning how many there were
+    pub fn kill_user(&self, user: &str) -> usize {
+        let sessions = self.0.lock().unwrap();
+        let mut killed = 0;
+        for session in sessions.values() {
+            if session.state().user.as_deref() == Some(user) {
+                session.kill.cancel();
+                killed += 1;
+            }
+        }
+        killed
+    }
+}
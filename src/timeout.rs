@@ -0,0 +1,38 @@
+//! # Timeout
+//! Limits on how long each phase of a session may take, in seconds.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::time::Duration;
+
+/// Session timeouts, every one left unset meaning no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Timeouts {
+    /// greeting, authentication and request
+    #[serde(default, deserialize_with = "secs", serialize_with = "as_secs")]
+    pub handshake: Option<Duration>,
+
+    /// outbound connection, replied to with `TTL_EXPIRED`
+    #[serde(default, deserialize_with = "secs", serialize_with = "as_secs")]
+    pub connect: Option<Duration>,
+
+    /// relay without bytes in either direction
+    #[serde(default, deserialize_with = "secs", serialize_with = "as_secs")]
+    pub idle: Option<Duration>,
+
+    /// whole session
+    #[serde(default, deserialize_with = "secs", serialize_with = "as_secs")]
+    pub lifetime: Option<Duration>,
+}
+
+fn secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    Option::<f64>::deserialize(deserializer)?
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(de::Error::custom)
+}
+
+fn as_secs<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    duration
+        .map(|duration| duration.as_secs_f64())
+        .serialize(serializer)
+}
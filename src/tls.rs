@@ -0,0 +1,155 @@
+//! # TLS
+//! Terminating TLS on a listener, so that the SOCKS handshake, passwords
+//! included, never travels in cleartext (needs the `tls` feature).
+//!
+//! With a client CA configured, clients may also present a certificate.
+//! Once verified, it stands for the user whose `cert_subject` is its
+//! subject or, for users without one, whose username is one of its common
+//! names or alternative names. Such clients authenticate without a password
+//! and may be revoked through CRL files.
+
+use crate::connection::PeerCert;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig, ServerConnection,
+};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// Certificate, key and client verification settings of a TLS listener
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Tls {
+    /// PEM file holding the certificate chain
+    pub cert: PathBuf,
+
+    /// PEM file holding the private key
+    pub key: PathBuf,
+
+    /// PEM file holding the CA certificates client certificates are
+    /// verified against, none being asked for when unset
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+
+    /// PEM files holding the CRLs client certificates are checked against
+    #[serde(default)]
+    pub crls: Vec<PathBuf>,
+}
+
+impl Tls {
+    /// Creates the settings of a listener without client certificates
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+            client_ca: None,
+            crls: vec![],
+        }
+    }
+
+    /// Verifies the client certificates against the CAs in `client_ca`
+    pub fn with_client_ca(mut self, client_ca: impl Into<PathBuf>) -> Self {
+        self.client_ca = Some(client_ca.into());
+        self
+    }
+
+    /// Rejects the client certificates revoked by the CRLs in `crls`
+    pub fn with_crls<P: Into<PathBuf>>(mut self, crls: impl IntoIterator<Item = P>) -> Self {
+        self.crls = crls.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Loads the certificates and key
+    pub(crate) fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?;
+
+        let builder = match self.client_ca {
+            Some(ref client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in certs(client_ca)? {
+                    roots.add(cert).map_err(io::Error::other)?;
+                }
+
+                let mut revoked = vec![];
+                for path in &self.crls {
+                    revoked.extend(crls(path)?);
+                }
+
+                // only client certificates themselves are checked, CRLs of
+                // intermediate CAs being seldom at hand
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .with_crls(revoked)
+                    .only_check_end_entity_revocation()
+                    .allow_unauthenticated()
+                    .build()
+                    .map_err(io::Error::other)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder
+            .with_single_cert(certs(&self.cert)?, key(&self.key)?)
+            .map_err(io::Error::other)?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Reads the verified certificate the client presented, if any
+pub(crate) fn peer_cert(conn: &ServerConnection) -> Option<PeerCert> {
+    let der = conn.peer_certificates()?.first()?;
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(String::from)
+        .collect();
+    if let Ok(Some(alt_names)) = cert.subject_alternative_name() {
+        names.extend(
+            alt_names
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => {
+                        Some(name.to_string())
+                    }
+                    _ => None,
+                }),
+        );
+    }
+
+    Some(PeerCert {
+        subject: cert.subject().to_string(),
+        names,
+    })
+}
+
+fn certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)).collect()
+}
+
+fn crls(path: &Path) -> io::Result<Vec<CertificateRevocationListDer<'static>>> {
+    rustls_pemfile::crls(&mut BufReader::new(File::open(path)?)).collect()
+}
+
+fn key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No private key in {}", path.display()),
+        )
+    })
+}
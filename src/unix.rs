@@ -0,0 +1,111 @@
+//! # Unix
+//! Serving local clients over a Unix domain socket instead of TCP.
+//!
+//! The credentials of the connecting process (`SO_PEERCRED`) come along with
+//! each session: routing rules can match their `uid`, and clients skipping
+//! authentication are taken for the allowed user with the same `uid`.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::os::unix::fs::{chown, DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::net::UnixListener;
+
+/// Where and how to create the socket file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UnixListen {
+    /// path of the socket file
+    pub path: PathBuf,
+
+    /// permissions of the socket file, written in octal as `"660"`
+    #[serde(default, deserialize_with = "octal", serialize_with = "as_octal")]
+    pub mode: Option<u32>,
+
+    /// user id owning the socket file
+    #[serde(default)]
+    pub owner: Option<u32>,
+
+    /// group id owning the socket file
+    #[serde(default)]
+    pub group: Option<u32>,
+}
+
+impl UnixListen {
+    /// Creates a socket at `path`, with the default permissions and owner
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            mode: None,
+            owner: None,
+            group: None,
+        }
+    }
+
+    /// Sets the permissions of the socket file
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Sets the user and group ids owning the socket file
+    pub fn with_owner(mut self, owner: Option<u32>, group: Option<u32>) -> Self {
+        self.owner = owner;
+        self.group = group;
+        self
+    }
+
+    /// Creates the socket file, replacing one left over by a previous run.
+    ///
+    /// The socket is bound inside a private directory and only renamed into
+    /// place once its permissions and owner are set, so nobody can connect
+    /// to it in between.
+    pub(crate) fn bind(&self) -> io::Result<UnixListener> {
+        let name = self.path.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("No file name in {}", self.path.display()),
+            )
+        })?;
+        let private = self.path.with_file_name(format!(
+            ".{}.{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        DirBuilder::new().mode(0o700).create(&private)?;
+
+        let staged = private.join(name);
+        let listener = self.stage(&staged);
+        let _ = fs::remove_file(&staged);
+        let _ = fs::remove_dir(&private);
+        listener
+    }
+
+    fn stage(&self, staged: &Path) -> io::Result<UnixListener> {
+        let listener = UnixListener::bind(staged)?;
+
+        if let Some(mode) = self.mode {
+            fs::set_permissions(staged, Permissions::from_mode(mode))?;
+        }
+        if self.owner.is_some() || self.group.is_some() {
+            chown(staged, self.owner, self.group)?;
+        }
+
+        if fs::symlink_metadata(&self.path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(&self.path)?;
+        }
+        fs::rename(staged, &self.path)?;
+
+        Ok(listener)
+    }
+}
+
+fn octal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|mode| u32::from_str_radix(&mode, 8).map_err(de::Error::custom))
+        .transpose()
+}
+
+fn as_octal<S: Serializer>(mode: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+    mode.map(|mode| format!("{mode:o}")).serialize(serializer)
+}
@@ -0,0 +1,277 @@
+//! # Upstream
+//! Dialing destinations through one or more parent proxies.
+//!
+//! A chain is a list of upstreams: the first one is connected to directly and
+//! each one is asked to connect to the next, the last one connecting to the
+//! destination itself. Domain names are handed to the last hop unresolved.
+
+use crate::destination::Destination;
+use crate::egress::{Affinity, Egress};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use socks_rs::{
+    auth::{AuthRequest, AuthResponse},
+    establish::{method, EstablishRequest, EstablishResponse},
+    reply::{reply_opt, Reply},
+    request::{addr_type, command, Request},
+    socks4, Sendible,
+};
+use std::{error, fmt, io};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// A parent proxy, serialized without its password
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Upstream {
+    /// a SOCKS5 proxy, with optional username/password authentication
+    Socks5 {
+        addr: Destination,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default, skip_serializing)]
+        password: Option<String>,
+    },
+
+    /// a SOCKS4a proxy
+    Socks4a {
+        addr: Destination,
+        #[serde(default)]
+        user_id: String,
+    },
+
+    /// an HTTP proxy supporting the CONNECT method, with optional basic authentication
+    HttpConnect {
+        addr: Destination,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default, skip_serializing)]
+        password: Option<String>,
+    },
+}
+
+/// Error carrying the reply code an upstream failed with, so that
+/// it can be passed back to the client as is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamError {
+    /// the SOCKS5 reply code matching the upstream failure
+    pub rep: u8,
+}
+
+impl Upstream {
+    /// The address of the proxy
+    pub fn addr(&self) -> &Destination {
+        match self {
+            Self::Socks5 { addr, .. }
+            | Self::Socks4a { addr, .. }
+            | Self::HttpConnect { addr, .. } => addr,
+        }
+    }
+
+    /// Asks the proxy at the other end of `stream` to connect to `dst`
+    async fn handshake(&self, stream: &mut TcpStream, dst: &Destination) -> io::Result<()> {
+        match self {
+            Self::Socks5 {
+                username, password, ..
+            } => socks5_handshake(stream, dst, username.as_deref(), password.as_deref()).await,
+            Self::Socks4a { user_id, .. } => socks4a_handshake(stream, dst, user_id).await,
+            Self::HttpConnect {
+                username, password, ..
+            } => http_handshake(stream, dst, username.as_deref(), password.as_deref()).await,
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upstream failed with reply {}", self.rep)
+    }
+}
+
+impl error::Error for UpstreamError {}
+
+impl From<UpstreamError> for io::Error {
+    fn from(err: UpstreamError) -> Self {
+        io::Error::new(io::ErrorKind::ConnectionAborted, err)
+    }
+}
+
+/// Connects to `dst` through every upstream of `chain`, in order,
+/// the first one being reached from `egress`
+pub(crate) async fn connect(
+    chain: &[Upstream],
+    dst: &Destination,
+    egress: &Egress,
+    affinity: Affinity<'_>,
+) -> io::Result<TcpStream> {
+    let Some(first) = chain.first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Empty upstream chain",
+        ));
+    };
+
+    let addr = match first.addr() {
+        Destination::Addr(addr) => *addr,
+        Destination::Domain(host, port) => tokio::net::lookup_host((host.as_str(), *port))
+            .await?
+            .find(|addr| {
+                egress
+                    .source(addr.ip(), affinity)
+                    .is_none_or(|ip| ip.is_ipv4() == addr.is_ipv4())
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Cannot resolve upstream {host}"),
+                )
+            })?,
+    };
+    let mut stream = egress.connect(addr, affinity).await?;
+
+    let hops = chain.iter().skip(1).map(Upstream::addr).chain([dst]);
+    for (upstream, next) in chain.iter().zip(hops) {
+        upstream.handshake(&mut stream, next).await?;
+    }
+
+    Ok(stream)
+}
+
+fn failure(rep: u8) -> io::Error {
+    UpstreamError { rep }.into()
+}
+
+async fn socks5_handshake(
+    stream: &mut TcpStream,
+    dst: &Destination,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> io::Result<()> {
+    let methods = match username {
+        Some(_) => &[
+            method::NO_AUTHENTICATION_REQUIRED,
+            method::USERNAME_PASSWORD,
+        ][..],
+        None => &[method::NO_AUTHENTICATION_REQUIRED][..],
+    };
+    stream
+        .write_all(&EstablishRequest::new(methods).serialize()?)
+        .await?;
+
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).await?;
+    match EstablishResponse::deserialize(&buf)?.method {
+        method::NO_AUTHENTICATION_REQUIRED => (),
+        method::USERNAME_PASSWORD if username.is_some() => {
+            let auth_request = AuthRequest::new(username.unwrap(), password.unwrap_or_default());
+            stream.write_all(&auth_request.serialize()?).await?;
+
+            stream.read_exact(&mut buf).await?;
+            if AuthResponse::deserialize(&buf)?.status != 0 {
+                return Err(failure(reply_opt::SOCKS_SERVER_FAILURE));
+            }
+        }
+        _ => return Err(failure(reply_opt::SOCKS_SERVER_FAILURE)),
+    }
+
+    let (atyp, dst_addr) = dst.to_socks();
+    let request = Request::new(command::CONNECT, atyp, &dst_addr, dst.port());
+    stream.write_all(&request.serialize()?).await?;
+
+    let mut buf = vec![0; 5];
+    stream.read_exact(&mut buf).await?;
+    let rest = match buf[3] {
+        addr_type::IP_V4 => 4 + 2 - 1,
+        addr_type::DOMAIN_NAME => buf[4] as usize + 2,
+        addr_type::IP_V6 => 16 + 2 - 1,
+        _ => return Err(failure(reply_opt::SOCKS_SERVER_FAILURE)),
+    };
+    buf.resize(5 + rest, 0);
+    stream.read_exact(&mut buf[5..]).await?;
+
+    match Reply::deserialize(&buf)?.rep {
+        reply_opt::SUCCEEDED => Ok(()),
+        rep => Err(failure(rep)),
+    }
+}
+
+async fn socks4a_handshake(
+    stream: &mut TcpStream,
+    dst: &Destination,
+    user_id: &str,
+) -> io::Result<()> {
+    let request = match dst {
+        Destination::Addr(addr) => match addr.ip() {
+            std::net::IpAddr::V4(ip) => {
+                socks4::Request::new(socks4::command::CONNECT, ip.octets(), addr.port(), user_id)
+            }
+            std::net::IpAddr::V6(_) => return Err(failure(reply_opt::ADDRESS_TYPE_NOT_SUPPORTED)),
+        },
+        Destination::Domain(host, port) => {
+            socks4::Request::with_domain(socks4::command::CONNECT, host, *port, user_id)
+        }
+    };
+    stream.write_all(&request.serialize()?).await?;
+
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf).await?;
+    match socks4::Reply::deserialize(&buf)?.cd {
+        socks4::reply_code::GRANTED => Ok(()),
+        socks4::reply_code::REJECTED => Err(failure(reply_opt::CONNECTION_REFUSED)),
+        _ => Err(failure(reply_opt::SOCKS_SERVER_FAILURE)),
+    }
+}
+
+async fn http_handshake(
+    stream: &mut TcpStream,
+    dst: &Destination,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> io::Result<()> {
+    // the name goes into the request line as is
+    if let Destination::Domain(host, _) = dst {
+        if !host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_'))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid host name {host:?}"),
+            ));
+        }
+    }
+
+    let mut request = format!("CONNECT {dst} HTTP/1.1\r\nHost: {dst}\r\n");
+    if let Some(username) = username {
+        let credentials = BASE64.encode(format!("{username}:{}", password.unwrap_or_default()));
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte so nothing past the header is consumed
+    let mut response = Vec::with_capacity(128);
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > 8192 {
+            return Err(failure(reply_opt::SOCKS_SERVER_FAILURE));
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let status = std::str::from_utf8(&response)
+        .ok()
+        .and_then(|response| response.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .unwrap_or_default();
+
+    match status {
+        200..=299 => Ok(()),
+        403 | 407 => Err(failure(reply_opt::CONNECTION_NOT_ALLOWED)),
+        404 | 502 => Err(failure(reply_opt::HOST_UNREACHABLE)),
+        503 => Err(failure(reply_opt::CONNECTION_REFUSED)),
+        504 => Err(failure(reply_opt::TTL_EXPIRED)),
+        _ => Err(failure(reply_opt::SOCKS_SERVER_FAILURE)),
+    }
+}
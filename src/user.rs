@@ -1,9 +1,24 @@
-use serde::Deserialize;
+use crate::egress::Egress;
+use crate::resolve::DnsPolicy;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::{Arc, RwLock};
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+/// An allowed user, serialized without its password
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct User {
     pub username: String,
+    #[serde(skip_serializing)]
     pub password: String,
+    #[serde(default)]
+    pub dns_policy: Option<DnsPolicy>,
+    #[serde(default)]
+    pub egress: Egress,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub cert_subject: Option<String>,
+    #[serde(default)]
+    pub disabled: bool,
 }
 
 #[allow(missing_docs, unused)]
@@ -13,6 +28,128 @@ impl User {
         Self {
             username: username.to_string(),
             password: password.to_string(),
+            dns_policy: None,
+            egress: Egress::default(),
+            uid: None,
+            cert_subject: None,
+            disabled: false,
         }
     }
+
+    #[inline]
+    pub fn with_dns_policy(mut self, dns_policy: DnsPolicy) -> Self {
+        self.dns_policy = Some(dns_policy);
+        self
+    }
+
+    #[inline]
+    pub fn with_egress(mut self, egress: Egress) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    #[inline]
+    pub fn with_uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    #[inline]
+    pub fn with_cert_subject(mut self, subject: &str) -> Self {
+        self.cert_subject = Some(subject.to_string());
+        self
+    }
+
+    #[inline]
+    pub fn with_disabled(mut self, disabled: bool) -> Self {
+        self.disabled = disabled;
+        self
+    }
+}
+
+/// Shared, reloadable list of allowed users.
+///
+/// Cloning a `Users` gives another handle to the same list. Disabled users
+/// are kept but cannot start sessions.
+#[derive(Debug, Clone, Default)]
+pub struct Users {
+    inner: Arc<RwLock<Vec<User>>>,
+}
+
+impl Users {
+    /// Creates an empty list
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the users
+    pub fn list(&self) -> Vec<User> {
+        self.inner.read().unwrap().clone()
+    }
+
+    /// Finds the first enabled user `pred` holds for
+    pub fn find(&self, pred: impl Fn(&User) -> bool) -> Option<User> {
+        self.inner
+            .read()
+            .unwrap()
+            .iter()
+            .find(|user| !user.disabled && pred(user))
+            .cloned()
+    }
+
+    /// Adds a user, replacing and returning the one with the same username
+    pub fn insert(&self, user: User) -> Option<User> {
+        let mut users = self.inner.write().unwrap();
+        match users.iter_mut().find(|u| u.username == user.username) {
+            Some(old) => Some(std::mem::replace(old, user)),
+            None => {
+                users.push(user);
+                None
+            }
+        }
+    }
+
+    /// Removes and returns the user with this username
+    pub fn remove(&self, username: &str) -> Option<User> {
+        let mut users = self.inner.write().unwrap();
+        let index = users.iter().position(|user| user.username == username)?;
+        Some(users.remove(index))
+    }
+
+    /// Disables or enables the user with this username, telling if there is one
+    pub fn set_disabled(&self, username: &str, disabled: bool) -> bool {
+        let mut users = self.inner.write().unwrap();
+        match users.iter_mut().find(|user| user.username == username) {
+            Some(user) => {
+                user.disabled = disabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Replaces all the users
+    pub fn replace(&self, users: Vec<User>) {
+        *self.inner.write().unwrap() = users;
+    }
+}
+
+impl From<Vec<User>> for Users {
+    fn from(users: Vec<User>) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(users)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Users {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl Serialize for Users {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.inner.read().unwrap().serialize(serializer)
+    }
 }
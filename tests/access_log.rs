@@ -0,0 +1,147 @@
+mod common;
+
+use proksi::{
+    access_log::{AccessLog, LogFormat, Sink},
+    user::User,
+    Server,
+};
+use socks_rs::{
+    establish::{method, EstablishRequest},
+    reply::reply_opt,
+    Sendible,
+};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    process::Command,
+    time,
+};
+
+#[tokio::test]
+async fn access_log() {
+    let access_log: AccessLog =
+        serde_json::from_str(r#"{ "sink": { "file": "/var/log/proksi.log" }, "format": "json" }"#)
+            .unwrap();
+    assert_eq!(
+        access_log,
+        AccessLog::new(Sink::File("/var/log/proksi.log".into())).with_format(LogFormat::Json)
+    );
+
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let path = std::env::temp_dir().join(format!("proksi-access-{}.log", std::process::id()));
+    let rotated = path.with_extension("log.1");
+    let _ = std::fs::remove_file(&path);
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::USERNAME_PASSWORD],
+        vec![User::new("alice", "1q2w3e4r")],
+    )
+    .unwrap()
+    .with_routes(serde_json::from_str(r#"[{ "port": [1], "action": "reject" }]"#).unwrap())
+    .with_access_log(AccessLog::new(Sink::File(path.clone())).with_format(LogFormat::Json))
+    .spawn()
+    .await
+    .unwrap();
+    let addr = server.local_addr();
+
+    let client = session(addr, echo_port, b"batata").await;
+    session(addr, 1, b"").await;
+
+    let lines = read_lines(&path).await;
+    assert_eq!(lines.len(), 2, "{lines:?}");
+
+    assert_eq!(lines[0]["client"], client.to_string());
+    assert_eq!(lines[0]["user"], "alice");
+    assert_eq!(lines[0]["command"], "CONNECT");
+    assert_eq!(lines[0]["destination"], format!("127.0.0.1:{echo_port}"));
+    assert_eq!(lines[0]["resolved"], format!("127.0.0.1:{echo_port}"));
+    let local: SocketAddr = lines[0]["local"].as_str().unwrap().parse().unwrap();
+    assert_eq!(local.ip(), client.ip());
+    assert_ne!(local, client);
+    assert_eq!(lines[0]["reply"], reply_opt::SUCCEEDED);
+    assert_eq!((&lines[0]["up"], &lines[0]["down"]), (&6.into(), &6.into()));
+    assert_eq!(lines[0]["close"], "closed");
+    assert!(lines[0]["timestamp"].as_str().unwrap().ends_with('Z'));
+    assert!(lines[0]["id"].as_u64().unwrap() < lines[1]["id"].as_u64().unwrap());
+
+    assert_eq!(lines[1]["reply"], reply_opt::CONNECTION_NOT_ALLOWED);
+    assert_eq!(lines[1]["resolved"], serde_json::Value::Null);
+    assert_eq!(lines[1]["local"], serde_json::Value::Null);
+    assert_eq!(lines[1]["close"], "Request to 127.0.0.1:1 rejected");
+
+    // logrotate moves the file away, then asks for a new one
+    std::fs::rename(&path, &rotated).unwrap();
+    let status = Command::new("kill")
+        .args(["-HUP", &std::process::id().to_string()])
+        .status()
+        .await
+        .unwrap();
+    assert!(status.success());
+    time::sleep(Duration::from_millis(100)).await;
+
+    session(addr, echo_port, b"").await;
+    assert_eq!(read_lines(&rotated).await.len(), 2);
+    assert_eq!(read_lines(&path).await.len(), 1);
+
+    // failed logins leave the attempted password out
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let user = Some(("alice", "hunter2"));
+    assert!(common::handshake(&mut stream, user).await.is_err());
+    stream.read_to_end(&mut vec![]).await.unwrap();
+
+    let lines = read_lines(&path).await;
+    assert!(lines[1]["close"].as_str().unwrap().contains("alice"));
+    assert!(!lines[1]["close"].as_str().unwrap().contains("hunter2"));
+
+    // sessions closed by the shutdown are logged too
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let methods = [method::USERNAME_PASSWORD];
+    stream
+        .write_all(&EstablishRequest::new(&methods).serialize().unwrap())
+        .await
+        .unwrap();
+    stream.read_exact(&mut [0; 2]).await.unwrap();
+    server.shutdown_now();
+    server.closed().await;
+
+    let lines = read_lines(&path).await;
+    assert_eq!(lines.len(), 3, "{lines:?}");
+    assert_eq!(lines[2]["close"], "Server shut down");
+
+    echo_handler.abort();
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&rotated);
+}
+
+/// Runs a whole session as alice, sending `data` and reading it back,
+/// and returns the client address
+async fn session(server: SocketAddr, port: u16, data: &[u8]) -> SocketAddr {
+    let user = Some(("alice", "1q2w3e4r"));
+    let (mut stream, rep) = common::connect(server, user, &[127, 0, 0, 1], port)
+        .await
+        .unwrap();
+    let client = stream.local_addr().unwrap();
+
+    if rep == reply_opt::SUCCEEDED {
+        common::echo(&mut stream, data).await.unwrap();
+    }
+
+    // the session is logged once both ends are closed
+    stream.shutdown().await.unwrap();
+    stream.read_to_end(&mut vec![]).await.unwrap();
+    client
+}
+
+/// Waits for the session logging to catch up, and reads the log lines
+async fn read_lines(path: &std::path::Path) -> Vec<serde_json::Value> {
+    time::sleep(Duration::from_millis(50)).await;
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
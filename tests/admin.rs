@@ -0,0 +1,231 @@
+mod common;
+
+use proksi::{
+    admin::{Admin, AdminListen},
+    user::User,
+    Server,
+};
+use serde_json::{json, Value};
+use socks_rs::{establish::method, reply::reply_opt};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+const TOKEN: &str = "s3cr3t";
+
+#[tokio::test]
+async fn admin() {
+    let admin: Admin = serde_json::from_str(
+        r#"{ "listen": { "tcp": "127.0.0.1:9091" }, "token": "s3cr3t", "config": "/etc/proksi.json" }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        admin,
+        Admin::new(AdminListen::Tcp("127.0.0.1:9091".parse().unwrap()), TOKEN)
+            .with_config("/etc/proksi.json")
+    );
+
+    let exposed = Server::new("127.0.0.1:0", vec![method::USERNAME_PASSWORD], vec![])
+        .unwrap()
+        .with_admin(Admin::new(
+            AdminListen::Tcp("0.0.0.0:0".parse().unwrap()),
+            TOKEN,
+        ))
+        .spawn()
+        .await;
+    assert_eq!(
+        exposed.unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let config = std::env::temp_dir().join(format!("proksi-admin-{}.json", std::process::id()));
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::USERNAME_PASSWORD],
+        vec![User::new("alice", "1q2w3e4r")],
+    )
+    .unwrap()
+    .with_admin(
+        Admin::new(AdminListen::Tcp("127.0.0.1:0".parse().unwrap()), TOKEN).with_config(&config),
+    )
+    .spawn()
+    .await
+    .unwrap();
+    let addr = server.local_addr();
+    let api = server.admin_addr().unwrap();
+
+    // no token, no service
+    let (status, _) = call(api, "GET", "/sessions", Some("wrong"), "").await;
+    assert_eq!(status, 401);
+    let (status, _) = call(api, "GET", "/sessions", None, "").await;
+    assert_eq!(status, 401);
+
+    // sessions are listed and killed
+    let (mut client, rep) = session(addr, "alice", "1q2w3e4r", echo_port).await.unwrap();
+    assert_eq!(rep, reply_opt::SUCCEEDED);
+    let (status, sessions) = call(api, "GET", "/sessions", Some(TOKEN), "").await;
+    assert_eq!(status, 200);
+    assert_eq!(sessions[0]["user"], "alice");
+    assert_eq!(sessions[0]["phase"], "relay");
+    assert_eq!(sessions[0]["command"], "CONNECT");
+
+    let id = sessions[0]["id"].as_u64().unwrap();
+    let (status, _) = call(api, "DELETE", &format!("/sessions/{id}"), Some(TOKEN), "").await;
+    assert_eq!(status, 200);
+    assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+    let (status, _) = call(api, "DELETE", &format!("/sessions/{id}"), Some(TOKEN), "").await;
+    assert_eq!(status, 404);
+
+    // users are added, disabled and removed
+    let bob = r#"{ "username": "bob", "password": "hunter2" }"#;
+    let (status, added) = call(api, "POST", "/users", Some(TOKEN), bob).await;
+    assert_eq!(status, 200);
+    assert_eq!(added["password"], Value::Null);
+    let (status, _) = call(api, "POST", "/users", Some(TOKEN), bob).await;
+    assert_eq!(status, 409);
+    assert!(session(addr, "bob", "hunter2", echo_port).await.is_some());
+
+    let (status, _) = call(api, "POST", "/users/bob/disable", Some(TOKEN), "").await;
+    assert_eq!(status, 200);
+    assert!(session(addr, "bob", "hunter2", echo_port).await.is_none());
+    call(api, "POST", "/users/bob/enable", Some(TOKEN), "").await;
+    assert!(session(addr, "bob", "hunter2", echo_port).await.is_some());
+
+    let (status, _) = call(api, "DELETE", "/users/bob", Some(TOKEN), "").await;
+    assert_eq!(status, 200);
+    assert!(session(addr, "bob", "hunter2", echo_port).await.is_none());
+    let (status, _) = call(api, "DELETE", "/users/%+6bob", Some(TOKEN), "").await;
+    assert_eq!(status, 400);
+    let (_, users) = call(api, "GET", "/users", Some(TOKEN), "").await;
+    assert_eq!(
+        users,
+        json!([{ "username": "alice", "dns_policy": null, "egress": users[0]["egress"], "uid": null, "cert_subject": null, "disabled": false }])
+    );
+
+    // routes and listener rules are replaced
+    let reject = format!(r#"[{{ "port": [{echo_port}], "action": "reject" }}]"#);
+    let (status, rules) = call(api, "PUT", "/routes", Some(TOKEN), &reject).await;
+    assert_eq!(status, 200);
+    assert_eq!(rules[0]["port"], json!([echo_port]));
+    assert_eq!(
+        session(addr, "alice", "1q2w3e4r", echo_port)
+            .await
+            .unwrap()
+            .1,
+        reply_opt::CONNECTION_NOT_ALLOWED
+    );
+    call(api, "PUT", "/routes", Some(TOKEN), "[]").await;
+
+    let (status, _) = call(api, "PUT", "/acls/0", Some(TOKEN), &reject).await;
+    assert_eq!(status, 200);
+    let (_, acls) = call(api, "GET", "/acls", Some(TOKEN), "").await;
+    assert_eq!(acls[0]["addr"], addr.to_string());
+    assert_eq!(
+        session(addr, "alice", "1q2w3e4r", echo_port)
+            .await
+            .unwrap()
+            .1,
+        reply_opt::CONNECTION_NOT_ALLOWED
+    );
+    call(api, "PUT", "/acls/0", Some(TOKEN), "[]").await;
+    let (status, _) = call(api, "PUT", "/acls/1", Some(TOKEN), "[]").await;
+    assert_eq!(status, 404);
+
+    // draining servers close new connections
+    let (status, drain) = call(api, "PUT", "/drain", Some(TOKEN), r#"{ "draining": true }"#).await;
+    assert_eq!((status, drain), (200, json!({ "draining": true })));
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(stream.read(&mut [0; 1]).await.unwrap(), 0);
+    call(
+        api,
+        "PUT",
+        "/drain",
+        Some(TOKEN),
+        r#"{ "draining": false }"#,
+    )
+    .await;
+    assert!(session(addr, "alice", "1q2w3e4r", echo_port)
+        .await
+        .is_some());
+
+    // the configuration shows neither passwords nor the token
+    let (status, effective) = call(api, "GET", "/config", Some(TOKEN), "").await;
+    assert_eq!(status, 200);
+    assert_eq!(effective["addr"][0]["addr"], addr.to_string());
+    assert_eq!(effective["admin"]["listen"]["tcp"], api.to_string());
+    assert!(!effective.to_string().contains("1q2w3e4r"));
+    assert!(!effective.to_string().contains(TOKEN));
+
+    // reloading applies the users of the file
+    std::fs::write(
+        &config,
+        r#"{ "addr": "127.0.0.1:1080", "auth": [2], "allowed_users": [{ "username": "carol", "password": "pw" }] }"#,
+    )
+    .unwrap();
+    let (status, _) = call(api, "POST", "/reload", Some(TOKEN), "").await;
+    assert_eq!(status, 200);
+    assert!(session(addr, "carol", "pw", echo_port).await.is_some());
+    assert!(session(addr, "alice", "1q2w3e4r", echo_port)
+        .await
+        .is_none());
+
+    std::fs::write(&config, "{").unwrap();
+    let (status, error) = call(api, "POST", "/reload", Some(TOKEN), "").await;
+    assert_eq!(status, 400, "{error}");
+
+    let (status, _) = call(api, "GET", "/reload", Some(TOKEN), "").await;
+    assert_eq!(status, 405);
+    let (status, _) = call(api, "GET", "/nothing", Some(TOKEN), "").await;
+    assert_eq!(status, 404);
+
+    server.shutdown_now();
+    echo_handler.abort();
+    let _ = std::fs::remove_file(&config);
+}
+
+/// Sends an API request and returns the status and JSON body of the response
+async fn call(
+    api: SocketAddr,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+    body: &str,
+) -> (u16, Value) {
+    let mut stream = TcpStream::connect(api).await.unwrap();
+    let auth = token
+        .map(|token| format!("Authorization: Bearer {token}\r\n"))
+        .unwrap_or_default();
+    let request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {api}\r\n{auth}Content-Length: {}\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let status = response[9..12].parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+/// Starts a session and requests a CONNECT to `port`, returning the client
+/// stream and the reply code if authentication went through
+async fn session(
+    server: SocketAddr,
+    username: &str,
+    password: &str,
+    port: u16,
+) -> Option<(TcpStream, u8)> {
+    let user = Some((username, password));
+    let session = common::connect(server, user, &[127, 0, 0, 1], port).await;
+
+    // give the session a moment to be seen relaying
+    time::sleep(Duration::from_millis(20)).await;
+    session.ok()
+}
@@ -0,0 +1,145 @@
+//! Helpers shared by the integration tests: a SOCKS5 client and an echo
+//! server to point it at.
+//!
+//! Each test crate only uses some of them.
+#![allow(dead_code)]
+
+use socks_rs::{
+    auth::{AuthRequest, AuthResponse},
+    establish::{method, EstablishRequest, EstablishResponse},
+    request::{addr_type, command, Request},
+    Sendible,
+};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Starts a server echoing back whatever it gets, returning its port on the
+/// loopback and the task to abort once done
+pub async fn echo_server() -> (u16, JoinHandle<()>) {
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = echo.local_addr().unwrap().port();
+    let handler = tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = socket.split();
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            });
+        }
+    });
+
+    (port, handler)
+}
+
+/// Offers no authentication, or username and password when `user` is set,
+/// failing if the server picks another method or refuses the credentials
+pub async fn handshake<S>(stream: &mut S, user: Option<(&str, &str)>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let methods = match user {
+        Some(_) => [method::USERNAME_PASSWORD],
+        None => [method::NO_AUTHENTICATION_REQUIRED],
+    };
+    stream
+        .write_all(&EstablishRequest::new(&methods).serialize()?)
+        .await?;
+
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).await?;
+    if EstablishResponse::deserialize(&buf)?.method != methods[0] {
+        return Err("No acceptable method".into());
+    }
+
+    if let Some((username, password)) = user {
+        stream
+            .write_all(&AuthRequest::new(username, password).serialize()?)
+            .await?;
+
+        stream.read_exact(&mut buf).await?;
+        if AuthResponse::deserialize(&buf)?.status != 0 {
+            return Err("Authentication failed".into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a `cmd` request for `port` on `dst`, an IPv4 address or a name
+pub async fn send_request<S>(stream: &mut S, cmd: u8, dst: &[u8], port: u16) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let atyp = match dst.len() {
+        4 => addr_type::IP_V4,
+        _ => addr_type::DOMAIN_NAME,
+    };
+    let request = Request::new(cmd, atyp, dst, port);
+    stream.write_all(&request.serialize()?).await?;
+    Ok(())
+}
+
+/// Reads a whole reply, whatever the type of its address
+pub async fn read_reply<S>(stream: &mut S) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut reply = vec![0; 4];
+    stream.read_exact(&mut reply).await?;
+
+    let len = match reply[3] {
+        addr_type::IP_V4 => 4,
+        addr_type::IP_V6 => 16,
+        _ => {
+            let len = stream.read_u8().await?;
+            reply.push(len);
+            len as usize
+        }
+    };
+    let start = reply.len();
+    reply.resize(start + len + 2, 0);
+    stream.read_exact(&mut reply[start..]).await?;
+
+    Ok(reply)
+}
+
+/// Sends a `cmd` request for `port` on `dst` and returns the reply code
+pub async fn request<S>(stream: &mut S, cmd: u8, dst: &[u8], port: u16) -> Result<u8>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    send_request(stream, cmd, dst, port).await?;
+    Ok(read_reply(stream).await?[1])
+}
+
+/// Connects to `server` and asks for a CONNECT to `port` on `dst`,
+/// returning the client stream and the reply code
+pub async fn connect(
+    server: SocketAddr,
+    user: Option<(&str, &str)>,
+    dst: &[u8],
+    port: u16,
+) -> Result<(TcpStream, u8)> {
+    let mut stream = TcpStream::connect(server).await?;
+    handshake(&mut stream, user).await?;
+    let rep = request(&mut stream, command::CONNECT, dst, port).await?;
+    Ok((stream, rep))
+}
+
+/// Sends `data` and checks it comes back unchanged
+pub async fn echo<S>(stream: &mut S, data: &[u8]) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(data).await?;
+    let mut echoed = vec![0; data.len()];
+    stream.read_exact(&mut echoed).await?;
+    assert_eq!(echoed, data);
+    Ok(())
+}
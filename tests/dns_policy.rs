@@ -0,0 +1,81 @@
+mod common;
+
+use proksi::{resolve::DnsPolicy, user::User, Server};
+use socks_rs::{establish::method, reply::reply_opt};
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn dns_policy() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let listener_handler = tokio::spawn(async move {
+        loop {
+            listener.accept().await.unwrap();
+        }
+    });
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![
+            method::NO_AUTHENTICATION_REQUIRED,
+            method::USERNAME_PASSWORD,
+        ],
+        vec![User::new("alice", "1q2w3e4r").with_dns_policy(DnsPolicy::Ipv4Only)],
+    )
+    .unwrap()
+    .with_dns_policy(DnsPolicy::Refuse);
+    let server = server.spawn().await.unwrap();
+
+    let (_, rep) = common::connect(server.local_addr(), None, b"localhost", port)
+        .await
+        .unwrap();
+    assert_eq!(rep, reply_opt::ADDRESS_TYPE_NOT_SUPPORTED);
+
+    let (_, rep) = common::connect(
+        server.local_addr(),
+        Some(("alice", "1q2w3e4r")),
+        b"localhost",
+        port,
+    )
+    .await
+    .unwrap();
+    assert_eq!(rep, reply_opt::SUCCEEDED);
+
+    server.shutdown_now();
+
+    // a listener policy applies to sessions without a user one
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![
+            method::NO_AUTHENTICATION_REQUIRED,
+            method::USERNAME_PASSWORD,
+        ],
+        vec![User::new("bob", "p@sSw0rd").with_dns_policy(DnsPolicy::Refuse)],
+    )
+    .unwrap()
+    .with_dns_policy(DnsPolicy::Refuse)
+    .with_listener(
+        serde_json::from_str(r#"{ "addr": "127.0.0.1:0", "dns_policy": "ipv4_only" }"#).unwrap(),
+    );
+    assert_eq!(server.addr[1].dns_policy, Some(DnsPolicy::Ipv4Only));
+    let server = server.spawn().await.unwrap();
+    let [refusing, resolving] = server.local_addrs() else {
+        panic!("expected two listeners");
+    };
+
+    let (_, rep) = common::connect(*refusing, None, b"localhost", port)
+        .await
+        .unwrap();
+    assert_eq!(rep, reply_opt::ADDRESS_TYPE_NOT_SUPPORTED);
+    let (_, rep) = common::connect(*resolving, None, b"localhost", port)
+        .await
+        .unwrap();
+    assert_eq!(rep, reply_opt::SUCCEEDED);
+    let (_, rep) = common::connect(*resolving, Some(("bob", "p@sSw0rd")), b"localhost", port)
+        .await
+        .unwrap();
+    assert_eq!(rep, reply_opt::ADDRESS_TYPE_NOT_SUPPORTED);
+
+    server.shutdown_now();
+    listener_handler.abort();
+}
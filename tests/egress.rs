@@ -0,0 +1,194 @@
+mod common;
+
+use proksi::{
+    egress::{Egress, Strategy},
+    user::User,
+    Server,
+};
+use socks_rs::{establish::method, reply::reply_opt};
+use std::net::{IpAddr, SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+#[tokio::test]
+async fn egress_bind() {
+    // answers with the address the connection came from
+    let whoami = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let whoami_port = whoami.local_addr().unwrap().port();
+    let whoami_handler = tokio::spawn(async move {
+        loop {
+            let (mut socket, addr) = whoami.accept().await.unwrap();
+            socket
+                .write_all(addr.ip().to_string().as_bytes())
+                .await
+                .unwrap();
+        }
+    });
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![
+            method::NO_AUTHENTICATION_REQUIRED,
+            method::USERNAME_PASSWORD,
+        ],
+        vec![
+            User::new("bob", "p@sSw0rd").with_egress(Egress::bind([127, 0, 0, 3].into())),
+            User::new("carol", "c4r0l").with_egress(Egress::pool(
+                vec![[127, 0, 0, 4].into(), [127, 0, 0, 5].into()],
+                Strategy::RoundRobin,
+            )),
+            User::new("dave", "d4v3").with_egress(Egress::prefix(
+                "127.0.1.0/24".parse().unwrap(),
+                Strategy::StickyDestination,
+            )),
+            User::new("erin", "3r1n").with_egress(Egress::prefix(
+                "127.0.2.0/30".parse().unwrap(),
+                Strategy::RoundRobin,
+            )),
+        ],
+    )
+    .unwrap()
+    .with_egress(Egress::bind([127, 0, 0, 2].into()));
+    let server = server.spawn().await.unwrap();
+    let addr = server.local_addr();
+
+    assert_eq!(
+        source(addr, None, whoami_port).await,
+        IpAddr::from([127, 0, 0, 2])
+    );
+
+    assert_eq!(
+        source(addr, Some(("bob", "p@sSw0rd")), whoami_port).await,
+        IpAddr::from([127, 0, 0, 3])
+    );
+
+    let mut sources = vec![];
+    for _ in 0..4 {
+        sources.push(source(addr, Some(("carol", "c4r0l")), whoami_port).await);
+    }
+    assert_ne!(sources[0], sources[1]);
+    assert_eq!(sources[0], sources[2]);
+    assert_eq!(sources[1], sources[3]);
+
+    let mut sources = vec![];
+    for _ in 0..2 {
+        sources.push(source(addr, Some(("dave", "d4v3")), whoami_port).await);
+    }
+    assert_eq!(sources[0], sources[1]);
+    assert!(matches!(sources[0], IpAddr::V4(ip) if ip.octets()[..3] == [127, 0, 1]));
+
+    // neither the network nor the broadcast address of the prefix is used
+    let mut sources = vec![];
+    for _ in 0..4 {
+        sources.push(source(addr, Some(("erin", "3r1n")), whoami_port).await);
+    }
+    let hosts: [IpAddr; 2] = [[127, 0, 2, 1].into(), [127, 0, 2, 2].into()];
+    assert_eq!(sources, [hosts[0], hosts[1], hosts[0], hosts[1]]);
+
+    #[cfg(feature = "bind")]
+    {
+        use socks_rs::{reply::Reply, request::command, Sendible};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        common::handshake(&mut stream, None).await.unwrap();
+        common::send_request(&mut stream, command::BIND, &[127, 0, 0, 1], 0)
+            .await
+            .unwrap();
+        let reply = common::read_reply(&mut stream).await.unwrap();
+        assert_eq!(Reply::deserialize(&reply).unwrap().bnd_addr, [127, 0, 0, 2]);
+    }
+
+    server.shutdown_now();
+    whoami_handler.abort();
+}
+
+/// The interface and mark need `CAP_NET_RAW` and `CAP_NET_ADMIN`, without
+/// which the test is skipped
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn egress_mark() {
+    let probe = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+    if let Err(err) = probe
+        .set_mark(0x2a)
+        .and_then(|()| probe.bind_device(Some(b"lo")))
+    {
+        eprintln!("skipping egress_mark: {err}");
+        return;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let mut egress = Egress::bind([127, 0, 0, 2].into());
+    egress.interface = Some("lo".into());
+    egress.mark = Some(0x2a);
+
+    let rules = r#"[{ "user": ["bob"], "action": "direct", "mark": 43 }]"#;
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![
+            method::NO_AUTHENTICATION_REQUIRED,
+            method::USERNAME_PASSWORD,
+        ],
+        vec![User::new("bob", "p@sSw0rd")],
+    )
+    .unwrap()
+    .with_egress(egress)
+    .with_routes(serde_json::from_str(rules).unwrap())
+    .spawn()
+    .await
+    .unwrap();
+    let addr = server.local_addr();
+
+    for (user, mark) in [(None, 0x2a), (Some(("bob", "p@sSw0rd")), 43)] {
+        // both ends stay open until the mark is read
+        let (client, accepted) = tokio::join!(
+            common::connect(addr, user, &[127, 0, 0, 1], port),
+            listener.accept()
+        );
+        let (_client, rep) = client.unwrap();
+        assert_eq!(rep, reply_opt::SUCCEEDED);
+
+        let (_socket, source) = accepted.unwrap();
+        assert_eq!(source.ip(), IpAddr::from([127, 0, 0, 2]));
+        assert_eq!(socket_mark(source), Some(mark));
+    }
+
+    server.shutdown_now();
+}
+
+/// Reads `SO_MARK` back from the socket of this process bound to `local`,
+/// the server running in the same process as the test
+#[cfg(target_os = "linux")]
+fn socket_mark(local: SocketAddr) -> Option<u32> {
+    use std::os::fd::BorrowedFd;
+
+    std::fs::read_dir("/proc/self/fd")
+        .unwrap()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .find_map(|fd| {
+            // SAFETY: the descriptor is only queried, which fails harmlessly
+            // if it gets closed meanwhile
+            let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+            let socket = socket2::SockRef::from(&fd);
+            match socket.local_addr().ok()?.as_socket()? == local {
+                true => socket.mark().ok(),
+                false => None,
+            }
+        })
+}
+
+/// Connects to the whoami server and returns the address it saw the
+/// connection come from
+async fn source(server: SocketAddr, user: Option<(&str, &str)>, port: u16) -> IpAddr {
+    let (mut stream, rep) = common::connect(server, user, &[127, 0, 0, 1], port)
+        .await
+        .unwrap();
+    assert_eq!(rep, reply_opt::SUCCEEDED);
+
+    let mut buf = String::new();
+    stream.read_to_string(&mut buf).await.unwrap();
+    buf.parse().unwrap()
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use proksi::{hosts::Target, Server};
+use socks_rs::{establish::method, reply::reply_opt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+#[tokio::test]
+async fn host_overrides() {
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .with_hosts(
+        HashMap::from([
+            (
+                "db.internal".to_string(),
+                Target::Addr([127, 0, 0, 1].into()),
+            ),
+            (
+                "*.corp".to_string(),
+                Target::Name("db.internal".to_string()),
+            ),
+        ])
+        .into(),
+    );
+    let hosts = server.hosts();
+    let server = server.spawn().await.unwrap();
+
+    assert!(connect_echo(server.local_addr(), "db.internal", echo_port)
+        .await
+        .is_ok());
+    assert!(connect_echo(server.local_addr(), "git.eu.corp", echo_port)
+        .await
+        .is_ok());
+
+    hosts.remove("db.internal");
+    hosts.insert("*.eu.corp", Target::Addr([127, 0, 0, 1].into()));
+    assert_eq!(
+        hosts.rewrite("git.us.corp"),
+        Target::Name("db.internal".into())
+    );
+    assert!(connect_echo(server.local_addr(), "git.eu.corp", echo_port)
+        .await
+        .is_ok());
+
+    server.shutdown_now();
+    echo_handler.abort();
+}
+
+async fn connect_echo(server: SocketAddr, host: &str, port: u16) -> common::Result<()> {
+    let (mut stream, rep) = common::connect(server, None, host.as_bytes(), port).await?;
+    assert_eq!(rep, reply_opt::SUCCEEDED);
+    common::echo(&mut stream, b"batatabanana").await
+}
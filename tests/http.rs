@@ -0,0 +1,142 @@
+mod common;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use proksi::{user::User, Server};
+use socks_rs::establish::method;
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[tokio::test]
+async fn http_connect() {
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::USERNAME_PASSWORD],
+        vec![User::new("alice", "1q2w3e4r")],
+    )
+    .unwrap()
+    .with_routes(serde_json::from_str(r#"[{ "port": [1], "action": "reject" }]"#).unwrap())
+    .spawn()
+    .await
+    .unwrap();
+    let addr = server.local_addr();
+    let alice = Some(("alice", "1q2w3e4r"));
+
+    let target = format!("127.0.0.1:{echo_port}");
+    let (mut stream, response) = connect(addr, &target, alice).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    stream.write_all(b"batata").await.unwrap();
+    let mut buf = [0; 6];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"batata");
+
+    let (_, response) = connect(addr, &target, None).await;
+    assert!(response.starts_with("HTTP/1.1 407"), "{response}");
+    assert!(response.contains("Proxy-Authenticate: Basic"));
+
+    let (_, response) = connect(addr, &target, Some(("alice", "wrong"))).await;
+    assert!(response.starts_with("HTTP/1.1 407"), "{response}");
+
+    let (_, response) = connect(addr, "127.0.0.1:1", alice).await;
+    assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+
+    server.shutdown_now();
+    echo_handler.abort();
+}
+
+#[tokio::test]
+async fn http_forward() {
+    let server: Server =
+        serde_json::from_str(r#"{ "addr": "127.0.0.1:0", "auth": [0], "forward_http": true }"#)
+            .unwrap();
+
+    // the origin server answers with the request it got
+    let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_port = origin.local_addr().unwrap().port();
+    let origin_handler = tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = origin.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(socket.read_u8().await.unwrap());
+                }
+
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                    request.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.write_all(&request).await.unwrap();
+            });
+        }
+    });
+
+    let server = server.spawn().await.unwrap();
+    let request = format!(
+        "GET http://127.0.0.1:{origin_port}/batata?q=1 HTTP/1.1\r\n\
+         Host: 127.0.0.1:{origin_port}\r\n\
+         Proxy-Connection: keep-alive\r\n\r\n"
+    );
+    let response = exchange(server.local_addr(), &request).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert!(response.contains("GET /batata?q=1 HTTP/1.1\r\n"));
+    assert!(response.contains("Connection: close\r\n"));
+    assert!(!response.contains("Proxy-Connection"));
+    server.shutdown_now();
+
+    // forwarding is off by default, leaving CONNECT alone
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .spawn()
+    .await
+    .unwrap();
+    let response = exchange(server.local_addr(), &request).await;
+    assert!(response.starts_with("HTTP/1.1 501"), "{response}");
+    server.shutdown_now();
+
+    origin_handler.abort();
+}
+
+/// Sends a `CONNECT` and reads the response head
+async fn connect(
+    server: SocketAddr,
+    target: &str,
+    user: Option<(&str, &str)>,
+) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(server).await.unwrap();
+
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some((username, password)) = user {
+        let credentials = BASE64.encode(format!("{username}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        response.push(stream.read_u8().await.unwrap());
+    }
+
+    (stream, String::from_utf8(response).unwrap())
+}
+
+/// Sends a request and reads everything until the connection closes
+async fn exchange(server: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(server).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use proksi::{
+    listener::Listener,
+    route::{Action, PortRange, Rule},
+    user::User,
+    Server,
+};
+use socks_rs::{establish::method, reply::reply_opt};
+use std::net::SocketAddr;
+
+const CONFIG: &str = r#"{
+    "addr": [
+        "127.0.0.1:0",
+        {
+            "addr": "[::1]:0",
+            "auth": [2],
+            "rules": [{ "port": [25], "action": "reject" }]
+        }
+    ],
+    "auth": [0]
+}"#;
+
+#[tokio::test]
+async fn multiple_listeners() {
+    let server: Server = serde_json::from_str(CONFIG).unwrap();
+    assert_eq!(server.addr.len(), 2);
+    assert_eq!(server.addr[1].auth, Some(vec![method::USERNAME_PASSWORD]));
+    assert_eq!(server.addr[1].rules.len(), 1);
+
+    let server: Server =
+        serde_json::from_str(r#"{ "addr": "127.0.0.1:1080", "auth": [0] }"#).unwrap();
+    assert_eq!(
+        server.addr,
+        vec![Listener::new("127.0.0.1:1080".parse().unwrap())]
+    );
+
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    // the admin listener asks for a password and rejects the echo port
+    let admin = Listener::new("127.0.0.1:0".parse().unwrap())
+        .with_auth(vec![method::USERNAME_PASSWORD])
+        .with_rules(vec![Rule {
+            port: vec![PortRange {
+                start: echo_port,
+                end: echo_port,
+            }],
+            ..Rule::new(Action::Reject)
+        }]);
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![User::new("alice", "1q2w3e4r")],
+    )
+    .unwrap()
+    .with_listener(admin)
+    .spawn()
+    .await
+    .unwrap();
+    let [public, admin] = server.local_addrs() else {
+        panic!("expected two listeners");
+    };
+    assert_ne!(public, admin);
+
+    assert_eq!(
+        request(*public, None, echo_port).await.unwrap(),
+        reply_opt::SUCCEEDED
+    );
+    assert!(request(*admin, None, echo_port).await.is_err());
+    assert_eq!(
+        request(*admin, Some(("alice", "1q2w3e4r")), echo_port)
+            .await
+            .unwrap(),
+        reply_opt::CONNECTION_NOT_ALLOWED
+    );
+
+    server.shutdown_now();
+    echo_handler.abort();
+}
+
+async fn request(server: SocketAddr, user: Option<(&str, &str)>, port: u16) -> common::Result<u8> {
+    let (_, rep) = common::connect(server, user, &[127, 0, 0, 1], port).await?;
+    Ok(rep)
+}
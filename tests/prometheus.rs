@@ -0,0 +1,101 @@
+mod common;
+
+use proksi::{metrics::Prometheus, user::User, Server};
+use socks_rs::{establish::method, reply::reply_opt};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+#[tokio::test]
+async fn prometheus() {
+    let prometheus: Prometheus =
+        serde_json::from_str(r#"{ "addr": "127.0.0.1:9090", "per_user": true }"#).unwrap();
+    assert_eq!(
+        prometheus,
+        Prometheus::new("127.0.0.1:9090".parse().unwrap()).with_per_user(true)
+    );
+
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::USERNAME_PASSWORD],
+        vec![User::new("alice", "1q2w3e4r")],
+    )
+    .unwrap()
+    .with_routes(serde_json::from_str(r#"[{ "port": [1], "action": "reject" }]"#).unwrap())
+    .with_prometheus(Prometheus::new("127.0.0.1:0".parse().unwrap()).with_per_user(true))
+    .spawn()
+    .await
+    .unwrap();
+    let addr = server.local_addr();
+    let metrics_addr = server.metrics_addr().unwrap();
+
+    assert_eq!(
+        session(addr, "1q2w3e4r", echo_port).await,
+        Some(reply_opt::SUCCEEDED)
+    );
+    assert_eq!(
+        session(addr, "1q2w3e4r", 1).await,
+        Some(reply_opt::CONNECTION_NOT_ALLOWED)
+    );
+    assert_eq!(session(addr, "wrong", echo_port).await, None);
+    time::sleep(Duration::from_millis(50)).await;
+
+    let response = get(metrics_addr, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    for line in [
+        "proksi_connections_accepted_total 3",
+        "proksi_connections_active 0",
+        "proksi_connections_rejected_total{reason=\"auth\"} 1",
+        "proksi_connections_rejected_total{reason=\"rule\"} 1",
+        "proksi_auth_total{result=\"success\",user=\"alice\"} 2",
+        "proksi_auth_total{result=\"failure\",user=\"alice\"} 1",
+        "proksi_requests_total{command=\"CONNECT\",reply=\"0\",user=\"alice\"} 1",
+        "proksi_requests_total{command=\"CONNECT\",reply=\"2\",user=\"alice\"} 1",
+        "proksi_relayed_bytes_total{direction=\"up\",user=\"alice\"} 6",
+        "proksi_relayed_bytes_total{direction=\"down\",user=\"alice\"} 6",
+        "proksi_connect_duration_seconds_count 1",
+    ] {
+        assert!(response.lines().any(|l| l == line), "{line} in {response}");
+    }
+
+    // rejected clients are not counted as failed handshakes
+    assert!(!response.contains("proksi_handshake_failures_total{"));
+
+    let response = get(metrics_addr, "/").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+
+    server.shutdown_now();
+    echo_handler.abort();
+}
+
+/// Runs a session as alice, echoing `batata` through it when it succeeds,
+/// and returns the reply code if authentication went through
+async fn session(server: SocketAddr, password: &str, port: u16) -> Option<u8> {
+    let user = Some(("alice", password));
+    let (mut stream, rep) = common::connect(server, user, &[127, 0, 0, 1], port)
+        .await
+        .ok()?;
+
+    if rep == reply_opt::SUCCEEDED {
+        common::echo(&mut stream, b"batata").await.unwrap();
+        stream.shutdown().await.unwrap();
+        stream.read_to_end(&mut vec![]).await.unwrap();
+    }
+    Some(rep)
+}
+
+async fn get(server: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(server).await.unwrap();
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {server}\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
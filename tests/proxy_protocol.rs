@@ -0,0 +1,226 @@
+mod common;
+
+use proksi::{listener::Listener, upstream::Upstream, Server};
+use socks_rs::{establish::method, reply::reply_opt, request::command};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[tokio::test]
+async fn proxy_protocol_inbound() {
+    let server: Server = serde_json::from_str(
+        r#"{
+            "addr": { "addr": "127.0.0.1:0", "proxy_protocol": ["10.0.0.0/8"] },
+            "auth": [0]
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        server.addr[0].proxy_protocol,
+        vec!["10.0.0.0/8".parse().unwrap()]
+    );
+
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    // clients behind the balancer in 192.0.2.0/24 are not allowed
+    let balancer = Listener::new("127.0.0.1:0".parse().unwrap())
+        .with_proxy_protocol(vec!["127.0.0.1/32".parse().unwrap()]);
+    let elsewhere = Listener::new("127.0.0.1:0".parse().unwrap())
+        .with_proxy_protocol(vec!["10.0.0.0/8".parse().unwrap()]);
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .with_listener(balancer)
+    .with_listener(elsewhere)
+    .with_routes(
+        serde_json::from_str(r#"[{ "client": ["192.0.2.0/24"], "action": "reject" }]"#).unwrap(),
+    )
+    .spawn()
+    .await
+    .unwrap();
+    let [_, balancer, elsewhere] = server.local_addrs() else {
+        panic!("expected three listeners");
+    };
+
+    let v1 = |client: &str| format!("PROXY TCP4 {client} 203.0.113.1 4321 1080\r\n").into_bytes();
+    assert_eq!(
+        request(*balancer, &v1("198.51.100.7"), echo_port)
+            .await
+            .unwrap(),
+        reply_opt::SUCCEEDED
+    );
+    assert_eq!(
+        request(*balancer, &v1("192.0.2.7"), echo_port)
+            .await
+            .unwrap(),
+        reply_opt::CONNECTION_NOT_ALLOWED
+    );
+
+    let v2 = |client: [u8; 4]| {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        header.extend([0x21, 0x11, 0, 12]);
+        header.extend(client);
+        header.extend([203, 0, 113, 1, 0x10, 0xe1, 0x04, 0x38]);
+        header
+    };
+    assert_eq!(
+        request(*balancer, &v2([198, 51, 100, 7]), echo_port)
+            .await
+            .unwrap(),
+        reply_opt::SUCCEEDED
+    );
+    assert_eq!(
+        request(*balancer, &v2([192, 0, 2, 7]), echo_port)
+            .await
+            .unwrap(),
+        reply_opt::CONNECTION_NOT_ALLOWED
+    );
+
+    // the header is required from trusted balancers, and ignored otherwise
+    assert!(request(*balancer, &[], echo_port).await.is_err());
+    assert_eq!(
+        request(*elsewhere, &[], echo_port).await.unwrap(),
+        reply_opt::SUCCEEDED
+    );
+
+    server.shutdown_now();
+    echo_handler.abort();
+}
+
+#[tokio::test]
+async fn proxy_protocol_outbound() {
+    // the backend answers with the header it got, up to the line ending or
+    // the 28 bytes of a version 2 one
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_addr = backend.local_addr().unwrap();
+    let backend_handler = tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = backend.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut header = vec![socket.read_u8().await.unwrap()];
+                if header[0] == b'P' {
+                    while !header.ends_with(b"\r\n") {
+                        header.push(socket.read_u8().await.unwrap());
+                    }
+                } else {
+                    header.resize(28, 0);
+                    socket.read_exact(&mut header[1..]).await.unwrap();
+                }
+                socket.write_all(&header).await.unwrap();
+            });
+        }
+    });
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .with_routes(
+        serde_json::from_str(r#"[{ "action": "direct", "proxy_protocol": "v2" }]"#).unwrap(),
+    )
+    .spawn()
+    .await
+    .unwrap();
+
+    let mut stream = connect(server.local_addr(), &[127, 0, 0, 1], backend_addr.port()).await;
+    let client = stream.local_addr().unwrap();
+    let mut header = [0; 28];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!(header[..12], *b"\r\n\r\n\0\r\nQUIT\n");
+    assert_eq!(header[12..16], [0x21, 0x11, 0, 12]);
+    assert_eq!(header[16..24], [127, 0, 0, 1, 127, 0, 0, 1]);
+    assert_eq!(header[24..26], client.port().to_be_bytes());
+    assert_eq!(header[26..], backend_addr.port().to_be_bytes());
+    server.shutdown_now();
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .with_routes(
+        serde_json::from_str(r#"[{ "action": "direct", "proxy_protocol": "v1" }]"#).unwrap(),
+    )
+    .spawn()
+    .await
+    .unwrap();
+
+    let mut stream = connect(server.local_addr(), &[127, 0, 0, 1], backend_addr.port()).await;
+    let client = stream.local_addr().unwrap();
+    let mut header = String::new();
+    while !header.ends_with("\r\n") {
+        header.push(stream.read_u8().await.unwrap() as char);
+    }
+    assert_eq!(
+        header,
+        format!(
+            "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\n",
+            client.port(),
+            backend_addr.port()
+        )
+    );
+    server.shutdown_now();
+
+    // through an upstream, the header still points to the destination and
+    // not to the first hop
+    let parent = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .spawn()
+    .await
+    .unwrap();
+    let chain = format!(
+        r#"[{{ "type": "socks5", "addr": "{}" }}]"#,
+        parent.local_addr()
+    );
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .with_named_upstream(
+        "parent",
+        serde_json::from_str::<Vec<Upstream>>(&chain).unwrap(),
+    )
+    .with_routes(
+        serde_json::from_str(r#"[{ "action": { "upstream": "parent" }, "proxy_protocol": "v2" }]"#)
+            .unwrap(),
+    );
+    let server = server.spawn().await.unwrap();
+
+    let mut stream = connect(server.local_addr(), b"localhost", backend_addr.port()).await;
+    let mut header = [0; 28];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!(header[20..24], [127, 0, 0, 1]);
+    assert_eq!(header[26..], backend_addr.port().to_be_bytes());
+    server.shutdown_now();
+    parent.shutdown_now();
+
+    backend_handler.abort();
+}
+
+/// Connects through the server to `port` on `dst`, an IPv4 address or a name
+async fn connect(server: SocketAddr, dst: &[u8], port: u16) -> TcpStream {
+    let (stream, rep) = common::connect(server, None, dst, port).await.unwrap();
+    assert_eq!(rep, reply_opt::SUCCEEDED);
+    stream
+}
+
+async fn request(server: SocketAddr, header: &[u8], port: u16) -> common::Result<u8> {
+    let mut stream = TcpStream::connect(server).await?;
+    stream.write_all(header).await?;
+    common::handshake(&mut stream, None).await?;
+    common::request(&mut stream, command::CONNECT, &[127, 0, 0, 1], port).await
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use proksi::{
+    destination::Destination,
+    hosts::Target,
+    route::{Action, RouteQuery, Rule},
+    upstream::Upstream,
+    user::User,
+    Server,
+};
+use socks_rs::{establish::method, reply::reply_opt};
+use std::net::SocketAddr;
+
+const RULES: &str = r#"[
+    { "name": "no-smtp", "port": [25, "465-587"], "action": "reject" },
+    { "name": "blocked", "domain_suffix": ["blocked.test"], "action": "reject" },
+    { "name": "alice-lo", "cidr": ["127.0.0.0/8"], "user": ["alice"], "action": { "interface": "lo" } },
+    { "name": "via-parent", "domain": ["echo.test"], "action": { "upstream": "parent" } },
+    { "name": "local", "client": ["127.0.0.1"], "action": "direct", "mark": 7 }
+]"#;
+
+#[tokio::test]
+async fn routing_rules() {
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let parent = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap();
+    parent
+        .hosts()
+        .insert("echo.test", Target::Addr([127, 0, 0, 1].into()));
+    let parent = parent.spawn().await.unwrap();
+
+    let parent_chain = vec![Upstream::Socks5 {
+        addr: parent.local_addr().into(),
+        username: None,
+        password: None,
+    }];
+    // nothing listens there, only requests routed elsewhere can succeed
+    let dead_chain = vec![Upstream::Socks5 {
+        addr: "127.0.0.1:1".parse().unwrap(),
+        username: None,
+        password: None,
+    }];
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![
+            method::NO_AUTHENTICATION_REQUIRED,
+            method::USERNAME_PASSWORD,
+        ],
+        vec![User::new("alice", "1q2w3e4r")],
+    )
+    .unwrap()
+    .with_upstream(dead_chain)
+    .with_named_upstream("parent", parent_chain)
+    .with_routes(serde_json::from_str(RULES).unwrap());
+    let routes = server.routes();
+    let server = server.spawn().await.unwrap();
+
+    let dry_run = |dst: &str, user, client: &str| {
+        let dst: Destination = dst.parse().unwrap();
+        let query = RouteQuery {
+            dst: &dst,
+            user,
+            client: Some(client.parse().unwrap()),
+            uid: None,
+        };
+        routes.evaluate(&query).and_then(|route| route.rule.name)
+    };
+    assert_eq!(
+        dry_run("mail.test:25", None, "127.0.0.1").as_deref(),
+        Some("no-smtp")
+    );
+    assert_eq!(
+        dry_run("1.1.1.1:500", None, "10.0.0.1").as_deref(),
+        Some("no-smtp")
+    );
+    assert_eq!(
+        dry_run("a.blocked.test:80", None, "127.0.0.1").as_deref(),
+        Some("blocked")
+    );
+    assert_eq!(dry_run("notblocked.test:80", None, "10.0.0.1"), None);
+    assert_eq!(
+        dry_run("127.0.0.2:80", Some("alice"), "10.0.0.1").as_deref(),
+        Some("alice-lo")
+    );
+    assert_eq!(
+        dry_run("echo.test:80", Some("alice"), "10.0.0.1").as_deref(),
+        Some("via-parent")
+    );
+
+    assert_eq!(
+        request(server.local_addr(), None, b"x.blocked.test", echo_port)
+            .await
+            .unwrap(),
+        reply_opt::CONNECTION_NOT_ALLOWED
+    );
+    assert_eq!(
+        request(server.local_addr(), None, &[127, 0, 0, 1], echo_port)
+            .await
+            .unwrap(),
+        reply_opt::SUCCEEDED
+    );
+    assert_eq!(
+        request(server.local_addr(), None, b"echo.test", echo_port)
+            .await
+            .unwrap(),
+        reply_opt::SUCCEEDED
+    );
+    assert_eq!(
+        request(
+            server.local_addr(),
+            Some(("alice", "1q2w3e4r")),
+            &[127, 0, 0, 1],
+            echo_port
+        )
+        .await
+        .unwrap(),
+        reply_opt::SUCCEEDED
+    );
+
+    routes.replace(vec![Rule::new(Action::Reject)]);
+    assert_eq!(
+        request(server.local_addr(), None, &[127, 0, 0, 1], echo_port)
+            .await
+            .unwrap(),
+        reply_opt::CONNECTION_NOT_ALLOWED
+    );
+
+    server.shutdown_now();
+    parent.shutdown_now();
+    echo_handler.abort();
+}
+
+async fn request(
+    server: SocketAddr,
+    user: Option<(&str, &str)>,
+    dst_addr: &[u8],
+    port: u16,
+) -> common::Result<u8> {
+    let (mut stream, rep) = common::connect(server, user, dst_addr, port).await?;
+
+    if rep == reply_opt::SUCCEEDED {
+        common::echo(&mut stream, b"batatabanana").await?;
+    }
+
+    Ok(rep)
+}
@@ -16,44 +16,50 @@ use tokio::{
 pub(crate) mod macros {
     macro_rules! start {
         ($s:expr) => {{
-            tokio::spawn(async move { $s.start().await.unwrap() })
+            $s.spawn().await.unwrap()
         }};
     }
 
     macro_rules! listener {
         ($addr:expr) => {{
             let listener = TcpListener::bind($addr).await.unwrap();
-            tokio::spawn(async move {
+            let port = listener.local_addr().unwrap().port();
+            let handler = tokio::spawn(async move {
                 loop {
                     let (_, addr) = listener.accept().await.unwrap();
                     println!(">> {addr:?} <<");
                 }
-            })
+            });
+            (handler, port)
         }};
     }
 }
 
 #[tokio::test]
 async fn server_request() {
-    let server_addr = "127.0.0.1:1080";
-
-    let server = Server::default();
-    let server_handler = start!(server);
-    let listener_handler = listener!("127.0.0.1:8080");
-
-    time::sleep(Duration::from_secs(1)).await;
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap();
+    let server_handle = start!(server);
+    let server_addr = server_handle.local_addr();
+    let (listener_handler, port) = listener!("127.0.0.1:0");
 
     let mut stream = TcpStream::connect(server_addr).await.unwrap();
 
     assert!(server_establish_test(&mut stream).await.is_ok());
-    assert!(server_request_test(&mut stream).await.is_ok());
+    assert!(server_request_test(&mut stream, port).await.is_ok());
 
     #[cfg(feature = "bind")]
     {
         let mut bind_stream = TcpStream::connect(server_addr).await.unwrap();
 
         assert!(server_establish_test(&mut bind_stream).await.is_ok());
-        assert!(server_bind_request_test(&mut bind_stream).await.is_ok());
+        assert!(server_bind_request_test(&mut bind_stream, port)
+            .await
+            .is_ok());
 
         let mut buf = Vec::with_capacity(50);
         bind_stream.read_buf(&mut buf).await.unwrap();
@@ -67,10 +73,12 @@ async fn server_request() {
     assert!(server_establish_test(&mut stream).await.is_ok());
     assert!(server_request_domain_test(&mut stream).await.is_ok());
 
-    server_handler.abort();
+    server_handle.shutdown_now();
     listener_handler.abort();
+    time::timeout(Duration::from_secs(1), server_handle.closed())
+        .await
+        .unwrap();
     time::sleep(Duration::from_secs(1)).await;
-    assert!(server_handler.is_finished());
     assert!(listener_handler.is_finished());
 }
 
@@ -93,8 +101,11 @@ async fn server_establish_test(stream: &mut TcpStream) -> Result<(), Box<dyn std
     Ok(())
 }
 
-async fn server_request_test(stream: &mut TcpStream) -> Result<(), Box<dyn std::error::Error>> {
-    let request = Request::new(command::CONNECT, addr_type::IP_V4, &[127, 0, 0, 1], 8080);
+async fn server_request_test(
+    stream: &mut TcpStream,
+    port: u16,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = Request::new(command::CONNECT, addr_type::IP_V4, &[127, 0, 0, 1], port);
     stream.write_all(&request.serialize()?).await?;
 
     let mut buf = Vec::with_capacity(50);
@@ -131,8 +142,9 @@ async fn server_request_domain_test(
 #[cfg(feature = "bind")]
 async fn server_bind_request_test(
     stream: &mut TcpStream,
+    port: u16,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let bind_request = Request::new(command::BIND, addr_type::IP_V4, &[127, 0, 0, 1], 8080);
+    let bind_request = Request::new(command::BIND, addr_type::IP_V4, &[127, 0, 0, 1], port);
     stream.write_all(&bind_request.serialize()?).await?;
 
     let mut buf = Vec::with_capacity(50);
@@ -0,0 +1,145 @@
+mod common;
+
+use proksi::{session::Phase, user::User, Server};
+use socks_rs::{
+    auth::AuthRequest,
+    establish::{method, EstablishRequest},
+    reply::reply_opt,
+    request::{addr_type, command, Request},
+    Sendible,
+};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+#[tokio::test]
+async fn sessions() {
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::USERNAME_PASSWORD],
+        vec![User::new("alice", "1q2w3e4r"), User::new("bob", "hunter2")],
+    )
+    .unwrap()
+    .spawn()
+    .await
+    .unwrap();
+    let addr = server.local_addr();
+
+    let mut alice = connect(addr, ("alice", "1q2w3e4r"), echo_port).await;
+    let mut alice_again = connect(addr, ("alice", "1q2w3e4r"), echo_port).await;
+    let mut bob = connect(addr, ("bob", "hunter2"), echo_port).await;
+    let greeting = TcpStream::connect(addr).await.unwrap();
+
+    common::echo(&mut alice, b"batata").await.unwrap();
+    time::sleep(Duration::from_millis(50)).await;
+
+    let sessions = server.sessions();
+    assert_eq!(sessions.len(), 4, "{sessions:?}");
+    assert!(sessions.windows(2).all(|pair| pair[0].id < pair[1].id));
+
+    let first = &sessions[0];
+    assert_eq!(first.peer, Some(alice.local_addr().unwrap()));
+    assert_eq!(first.user.as_deref(), Some("alice"));
+    assert_eq!(first.command, Some("CONNECT"));
+    assert_eq!(first.destination, Some(format!("127.0.0.1:{echo_port}")));
+    assert_eq!((first.up, first.down), (6, 6));
+    assert_eq!(first.phase, Phase::Relay);
+
+    assert_eq!(sessions[2].user.as_deref(), Some("bob"));
+    assert_eq!(sessions[3].phase, Phase::Handshake);
+    assert_eq!(sessions[3].user, None);
+
+    assert!(server.kill_session(sessions[2].id));
+    assert_eq!(bob.read(&mut [0; 1]).await.unwrap(), 0);
+    assert!(!server.kill_session(sessions[2].id));
+
+    assert_eq!(server.kill_user_sessions("alice"), 2);
+    assert_eq!(alice.read(&mut [0; 1]).await.unwrap(), 0);
+    assert_eq!(alice_again.read(&mut [0; 1]).await.unwrap(), 0);
+    assert_eq!(server.kill_user_sessions("alice"), 0);
+
+    let sessions = server.sessions();
+    assert_eq!(sessions.len(), 1, "{sessions:?}");
+    assert_eq!(sessions[0].phase, Phase::Handshake);
+
+    drop(greeting);
+    server.shutdown_now();
+    echo_handler.abort();
+}
+
+/// Clients closing anywhere in the handshake leave no session behind
+#[tokio::test]
+async fn truncated_handshakes() {
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::USERNAME_PASSWORD],
+        vec![User::new("alice", "1q2w3e4r")],
+    )
+    .unwrap();
+    let metrics = server.metrics();
+    let server = server.spawn().await.unwrap();
+    let addr = server.local_addr();
+
+    // health checks and scanners close early, or send bits of messages
+    let greeting = EstablishRequest::new(&[method::USERNAME_PASSWORD])
+        .serialize()
+        .unwrap();
+    let auth = AuthRequest::new("alice", "1q2w3e4r").serialize().unwrap();
+    let request = Request::new(command::CONNECT, addr_type::IP_V4, &[127, 0, 0, 1], 80)
+        .serialize()
+        .unwrap();
+    for sent in [
+        vec![],
+        vec![5],
+        vec![5, 3, 0],
+        [greeting.as_slice(), &[1, 5, b'a']].concat(),
+        [greeting.as_slice(), &auth, &request[..8]].concat(),
+        [greeting.as_slice(), &auth, &[5, 1, 0, 3]].concat(),
+    ] {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for part in split(&sent, greeting.len(), auth.len()) {
+            stream.write_all(part).await.unwrap();
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        stream.shutdown().await.unwrap();
+        let _ = stream.read_to_end(&mut vec![]).await;
+    }
+    time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(server.sessions(), vec![]);
+    assert_eq!(metrics.sessions(), 6);
+    assert_eq!(metrics.active(), 0);
+
+    server.shutdown_now();
+}
+
+/// Splits what a client sends into the messages it sends one at a time
+fn split(sent: &[u8], greeting: usize, auth: usize) -> Vec<&[u8]> {
+    let mut parts = vec![];
+    let mut rest = sent;
+    for len in [greeting, auth] {
+        if rest.len() <= len {
+            break;
+        }
+        let (part, tail) = rest.split_at(len);
+        parts.push(part);
+        rest = tail;
+    }
+    parts.push(rest);
+    parts
+}
+
+/// Opens a tunnel to `port` on the loopback as `user`
+async fn connect(server: SocketAddr, user: (&str, &str), port: u16) -> TcpStream {
+    let (stream, rep) = common::connect(server, Some(user), &[127, 0, 0, 1], port)
+        .await
+        .unwrap();
+    assert_eq!(rep, reply_opt::SUCCEEDED);
+    stream
+}
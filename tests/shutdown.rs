@@ -0,0 +1,96 @@
+mod common;
+
+use proksi::Server;
+use socks_rs::{establish::method, reply::reply_opt};
+use std::net::SocketAddr;
+use tokio::time::{self, Duration};
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+#[tokio::test]
+async fn graceful_shutdown() {
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let handle = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .spawn()
+    .await
+    .unwrap();
+    assert_ne!(handle.local_addr().port(), 0);
+
+    // sessions open before the shutdown drain
+    let mut stream = request(handle.local_addr(), echo_port).await.unwrap();
+    let shutdown = tokio::spawn({
+        let handle = handle.clone();
+        async move { handle.shutdown(Duration::from_secs(10)).await }
+    });
+    time::sleep(Duration::from_millis(200)).await;
+
+    assert!(TcpStream::connect(handle.local_addr()).await.is_err());
+    assert!(!shutdown.is_finished());
+
+    common::echo(&mut stream, b"batatabanana").await.unwrap();
+
+    drop(stream);
+    time::timeout(Duration::from_secs(2), shutdown)
+        .await
+        .expect("shutdown should end with the last session")
+        .unwrap();
+    time::timeout(Duration::from_secs(2), handle.closed())
+        .await
+        .unwrap();
+
+    // past the deadline, sessions are closed
+    let handle = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .spawn()
+    .await
+    .unwrap();
+    let mut stream = request(handle.local_addr(), echo_port).await.unwrap();
+
+    time::timeout(
+        Duration::from_secs(2),
+        handle.shutdown(Duration::from_millis(200)),
+    )
+    .await
+    .expect("shutdown should not wait past its deadline");
+
+    let mut buf = vec![];
+    assert_eq!(stream.read_to_end(&mut buf).await.unwrap_or(0), 0);
+
+    // or right away
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap();
+    let metrics = server.metrics();
+    let handle = server.spawn().await.unwrap();
+    let mut stream = request(handle.local_addr(), echo_port).await.unwrap();
+    assert_eq!(metrics.active(), 1);
+
+    handle.shutdown_now();
+    time::timeout(Duration::from_secs(2), handle.closed())
+        .await
+        .unwrap();
+    assert_eq!(metrics.active(), 0);
+
+    let mut buf = vec![];
+    assert_eq!(stream.read_to_end(&mut buf).await.unwrap_or(0), 0);
+
+    echo_handler.abort();
+}
+
+async fn request(server: SocketAddr, port: u16) -> common::Result<TcpStream> {
+    let (stream, rep) = common::connect(server, None, &[127, 0, 0, 1], port).await?;
+    assert_eq!(rep, reply_opt::SUCCEEDED);
+    Ok(stream)
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use proksi::{listener::Listener, Server};
+use socks_rs::{
+    establish::method,
+    socks4::{command, reply_code, Reply, Request},
+    Sendible,
+};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+#[tokio::test]
+async fn socks4_request() {
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    // SOCKS4 shares the routing table, and cannot pass a password listener
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .with_listener(
+        Listener::new("127.0.0.1:0".parse().unwrap()).with_auth(vec![method::USERNAME_PASSWORD]),
+    )
+    .with_routes(serde_json::from_str(r#"[{ "port": [1], "action": "reject" }]"#).unwrap())
+    .spawn()
+    .await
+    .unwrap();
+    let [open, password] = server.local_addrs() else {
+        panic!("expected two listeners");
+    };
+
+    let request = Request::new(command::CONNECT, [127, 0, 0, 1], echo_port, "batata");
+    assert_eq!(connect(*open, request).await.unwrap(), reply_code::GRANTED);
+
+    let request = Request::with_domain(command::CONNECT, "localhost", echo_port, "");
+    assert_eq!(connect(*open, request).await.unwrap(), reply_code::GRANTED);
+
+    let request = Request::new(command::CONNECT, [127, 0, 0, 1], 1, "");
+    assert_eq!(connect(*open, request).await.unwrap(), reply_code::REJECTED);
+
+    let request = Request::new(command::CONNECT, [127, 0, 0, 1], echo_port, "");
+    assert_eq!(
+        connect(*password, request).await.unwrap(),
+        reply_code::REJECTED
+    );
+
+    server.shutdown_now();
+    echo_handler.abort();
+}
+
+/// Sends a SOCKS4 request and checks the relay when it is granted
+async fn connect(server: SocketAddr, request: Request<'_>) -> std::io::Result<u8> {
+    let mut stream = TcpStream::connect(server).await?;
+    stream.write_all(&request.serialize()?).await?;
+
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf).await?;
+    let reply = Reply::deserialize(&buf)?;
+
+    if reply.cd == reply_code::GRANTED {
+        stream.write_all(b"batata").await?;
+        let mut buf = [0; 6];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"batata");
+    }
+
+    Ok(reply.cd)
+}
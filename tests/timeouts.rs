@@ -0,0 +1,95 @@
+mod common;
+
+use proksi::{timeout::Timeouts, upstream::Upstream, Server};
+use socks_rs::{establish::method, reply::reply_opt, request::command};
+use std::net::SocketAddr;
+use tokio::time::{self, Duration};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+};
+
+#[tokio::test]
+async fn timeouts() {
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let timeouts = Timeouts {
+        handshake: Some(Duration::from_millis(300)),
+        connect: Some(Duration::from_millis(300)),
+        idle: Some(Duration::from_millis(300)),
+        lifetime: None,
+    };
+    for invalid in [r#"{ "idle": -1 }"#, r#"{ "lifetime": 1e300 }"#] {
+        assert!(serde_json::from_str::<Timeouts>(invalid).is_err());
+    }
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .with_timeouts(timeouts);
+    let metrics = server.metrics();
+    let server = server.spawn().await.unwrap();
+
+    // accepts connections and never answers
+    let stalled = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stalled_addr = stalled.local_addr().unwrap();
+    let stalled_handler = tokio::spawn(async move {
+        let mut sockets = vec![];
+        loop {
+            sockets.push(stalled.accept().await.unwrap().0);
+        }
+    });
+    let stalled_chain = vec![Upstream::Socks5 {
+        addr: stalled_addr.into(),
+        username: None,
+        password: None,
+    }];
+    let stalling = Server::new("127.0.0.1:0", vec![0], vec![])
+        .unwrap()
+        .with_upstream(stalled_chain)
+        .with_timeouts(timeouts);
+    let stalling = stalling.spawn().await.unwrap();
+
+    // never greets
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    let mut buf = vec![];
+    let read = time::timeout(Duration::from_secs(2), stream.read_to_end(&mut buf))
+        .await
+        .expect("handshake timeout should close the socket");
+    assert_eq!(read.unwrap_or(0), 0);
+    assert_eq!(metrics.handshake_timeouts(), 1);
+
+    // idle tunnel
+    let mut stream = request(server.local_addr(), echo_port).await.unwrap();
+    let reply = common::read_reply(&mut stream).await.unwrap();
+    assert_eq!(reply[1], reply_opt::SUCCEEDED);
+
+    common::echo(&mut stream, b"batatabanana").await.unwrap();
+    let mut buf = vec![];
+    let read = time::timeout(Duration::from_secs(2), stream.read_to_end(&mut buf))
+        .await
+        .expect("idle timeout should close the tunnel");
+    assert_eq!(read.unwrap_or(0), 0);
+
+    // upstream never answers
+    let mut stream = request(stalling.local_addr(), echo_port).await.unwrap();
+    let reply = common::read_reply(&mut stream).await.unwrap();
+    assert_eq!(reply[1], reply_opt::TTL_EXPIRED);
+
+    server.shutdown_now();
+    stalling.shutdown_now();
+    stalled_handler.abort();
+    echo_handler.abort();
+}
+
+/// Sends a CONNECT request to `127.0.0.1:port`, returning the stream before
+/// reading the reply
+async fn request(server: SocketAddr, port: u16) -> common::Result<TcpStream> {
+    let mut stream = TcpStream::connect(server).await?;
+    common::handshake(&mut stream, None).await?;
+    common::send_request(&mut stream, command::CONNECT, &[127, 0, 0, 1], port).await?;
+    Ok(stream)
+}
@@ -0,0 +1,232 @@
+#![cfg(feature = "tls")]
+
+mod common;
+
+use proksi::{listener::Listener, tls::Tls, user::User, Server};
+use rcgen::{
+    date_time_ymd, BasicConstraints, Certificate, CertificateParams,
+    CertificateRevocationListParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyIdMethod, KeyPair,
+    RevokedCertParams, SerialNumber,
+};
+use socks_rs::{establish::method, reply::reply_opt, request::command};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{PrivateKeyDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+
+#[tokio::test]
+async fn tls_listener() {
+    let server: Server = serde_json::from_str(
+        r#"{
+            "addr": {
+                "addr": "127.0.0.1:0",
+                "tls": { "cert": "cert.pem", "key": "key.pem", "client_ca": "ca.pem" }
+            },
+            "auth": [0]
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        server.addr[0].tls,
+        Some(Tls::new("cert.pem", "key.pem").with_client_ca("ca.pem"))
+    );
+
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let dir = std::env::temp_dir().join(format!("proksi-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(vec![]).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "proksi test CA");
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let server_key = KeyPair::generate().unwrap();
+    let server_cert = CertificateParams::new(vec!["localhost".into()])
+        .unwrap()
+        .signed_by(&server_key, &ca, &ca_key)
+        .unwrap();
+
+    let client_key = KeyPair::generate().unwrap();
+    let mut client_params = CertificateParams::new(vec![]).unwrap();
+    client_params
+        .distinguished_name
+        .push(DnType::CommonName, "alice");
+    client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client_cert = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+    std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    std::fs::write(dir.join("cert.pem"), server_cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), server_key.serialize_pem()).unwrap();
+
+    // only the certificate of alice reaches the echo server
+    let tls =
+        Tls::new(dir.join("cert.pem"), dir.join("key.pem")).with_client_ca(dir.join("ca.pem"));
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![User::new("alice", "").with_cert_subject("CN=alice")],
+    )
+    .unwrap()
+    .with_listener(Listener::new("127.0.0.1:0".parse().unwrap()).with_tls(tls))
+    .with_routes(
+        serde_json::from_str(
+            r#"[{ "user": ["alice"], "action": "direct" }, { "action": "reject" }]"#,
+        )
+        .unwrap(),
+    )
+    .spawn()
+    .await
+    .unwrap();
+    let [plain, tls] = server.local_addrs() else {
+        panic!("expected two listeners");
+    };
+
+    let identity = (&client_cert, &client_key);
+    assert_eq!(
+        request(*tls, &ca, Some(identity), echo_port).await.unwrap(),
+        reply_opt::SUCCEEDED
+    );
+    assert_eq!(
+        request(*tls, &ca, None, echo_port).await.unwrap(),
+        reply_opt::CONNECTION_NOT_ALLOWED
+    );
+
+    // the plain listener speaks no TLS
+    assert!(request(*plain, &ca, Some(identity), echo_port)
+        .await
+        .is_err());
+
+    server.shutdown_now();
+    echo_handler.abort();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn certificate_authentication() {
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let dir = std::env::temp_dir().join(format!("proksi-mtls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(vec![]).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let server_key = KeyPair::generate().unwrap();
+    let server_cert = CertificateParams::new(vec!["localhost".into()])
+        .unwrap()
+        .signed_by(&server_key, &ca, &ca_key)
+        .unwrap();
+
+    // bob is named by his email address, carol's certificate is revoked
+    let client = |serial: u64, name: &str| {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+        params.serial_number = Some(SerialNumber::from(serial));
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        (params.signed_by(&key, &ca, &ca_key).unwrap(), key)
+    };
+    let bob = client(2, "bob@example.com");
+    let carol = client(3, "carol");
+
+    let crl = CertificateRevocationListParams {
+        this_update: date_time_ymd(2024, 1, 1),
+        next_update: date_time_ymd(2099, 1, 1),
+        crl_number: SerialNumber::from(1),
+        issuing_distribution_point: None,
+        revoked_certs: vec![RevokedCertParams {
+            serial_number: SerialNumber::from(3),
+            revocation_time: date_time_ymd(2024, 1, 1),
+            reason_code: None,
+            invalidity_date: None,
+        }],
+        key_identifier_method: KeyIdMethod::Sha256,
+    }
+    .signed_by(&ca, &ca_key)
+    .unwrap();
+
+    std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    std::fs::write(dir.join("crl.pem"), crl.pem().unwrap()).unwrap();
+    std::fs::write(dir.join("cert.pem"), server_cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), server_key.serialize_pem()).unwrap();
+
+    // the listener asks for passwords, which certificates stand in for
+    let tls = Tls::new(dir.join("cert.pem"), dir.join("key.pem"))
+        .with_client_ca(dir.join("ca.pem"))
+        .with_crls([dir.join("crl.pem")]);
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::USERNAME_PASSWORD],
+        vec![
+            User::new("bob@example.com", "1q2w3e4r"),
+            User::new("carol", "1q2w3e4r"),
+        ],
+    )
+    .unwrap()
+    .with_listener(Listener::new("127.0.0.1:0".parse().unwrap()).with_tls(tls))
+    .with_routes(
+        serde_json::from_str(
+            r#"[{ "user": ["bob@example.com"], "action": "direct" }, { "action": "reject" }]"#,
+        )
+        .unwrap(),
+    )
+    .spawn()
+    .await
+    .unwrap();
+    let tls = server.local_addrs()[1];
+
+    assert_eq!(
+        request(tls, &ca, Some((&bob.0, &bob.1)), echo_port)
+            .await
+            .unwrap(),
+        reply_opt::SUCCEEDED
+    );
+    assert!(request(tls, &ca, None, echo_port).await.is_err());
+    assert!(request(tls, &ca, Some((&carol.0, &carol.1)), echo_port)
+        .await
+        .is_err());
+
+    server.shutdown_now();
+    echo_handler.abort();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+async fn request(
+    server: SocketAddr,
+    ca: &Certificate,
+    identity: Option<(&Certificate, &KeyPair)>,
+    port: u16,
+) -> common::Result<u8> {
+    let mut roots = RootCertStore::empty();
+    roots.add(ca.der().clone())?;
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some((cert, key)) => config.with_client_auth_cert(
+            vec![cert.der().clone()],
+            PrivateKeyDer::try_from(key.serialize_der())?,
+        )?,
+        None => config.with_no_client_auth(),
+    };
+
+    let stream = TcpStream::connect(server).await?;
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost")?, stream)
+        .await?;
+
+    common::handshake(&mut stream, None).await?;
+    common::request(&mut stream, command::CONNECT, &[127, 0, 0, 1], port).await
+}
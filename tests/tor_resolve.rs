@@ -0,0 +1,89 @@
+mod common;
+
+use proksi::{hosts::Target, Server};
+use socks_rs::{
+    establish::method,
+    reply::{reply_opt, Reply},
+    request::{addr_type, command},
+    Sendible,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn tor_resolve() {
+    let server: Server =
+        serde_json::from_str(r#"{ "addr": "127.0.0.1:0", "auth": [0], "tor_resolve": true }"#)
+            .unwrap();
+    let server = server
+        .with_hosts(
+            HashMap::from([(
+                "db.internal".to_string(),
+                Target::Addr([10, 1, 2, 3].into()),
+            )])
+            .into(),
+        )
+        .with_routes(
+            serde_json::from_str(r#"[{ "domain_suffix": ["blocked"], "action": "reject" }]"#)
+                .unwrap(),
+        )
+        .spawn()
+        .await
+        .unwrap();
+    let addr = server.local_addr();
+
+    let (rep, atyp, resolved) = resolve(addr, command::RESOLVE, b"db.internal")
+        .await
+        .unwrap();
+    assert_eq!(
+        (rep, atyp, resolved),
+        (reply_opt::SUCCEEDED, addr_type::IP_V4, vec![10, 1, 2, 3])
+    );
+
+    let (rep, ..) = resolve(addr, command::RESOLVE, b"git.blocked")
+        .await
+        .unwrap();
+    assert_eq!(rep, reply_opt::CONNECTION_NOT_ALLOWED);
+
+    if cfg!(feature = "dns-lookup") {
+        let (rep, atyp, resolved) = resolve(addr, command::RESOLVE_PTR, &[127, 0, 0, 1])
+            .await
+            .unwrap();
+        assert_eq!((rep, atyp), (reply_opt::SUCCEEDED, addr_type::DOMAIN_NAME));
+        assert!(!resolved.is_empty());
+    }
+    server.shutdown_now();
+
+    // the commands are off by default
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .spawn()
+    .await
+    .unwrap();
+    for (cmd, addr) in [
+        (command::RESOLVE, &b"localhost"[..]),
+        (command::RESOLVE_PTR, &[127, 0, 0, 1]),
+        (command::UDP_ASSOCIATE, &[127, 0, 0, 1]),
+    ] {
+        let (rep, ..) = resolve(server.local_addr(), cmd, addr).await.unwrap();
+        assert_eq!(rep, reply_opt::COMMAND_NOT_SUPPORTED);
+    }
+    server.shutdown_now();
+}
+
+/// Sends a `cmd` request, RESOLVE or RESOLVE_PTR mostly, and returns the
+/// reply code and the address or name replied with
+async fn resolve(server: SocketAddr, cmd: u8, addr: &[u8]) -> common::Result<(u8, u8, Vec<u8>)> {
+    let mut stream = TcpStream::connect(server).await?;
+    common::handshake(&mut stream, None).await?;
+    common::send_request(&mut stream, cmd, addr, 0).await?;
+
+    let reply = common::read_reply(&mut stream).await?;
+    let reply = Reply::deserialize(&reply)?;
+    Ok((reply.rep, reply.atyp, reply.bnd_addr.to_vec()))
+}
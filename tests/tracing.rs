@@ -0,0 +1,71 @@
+mod common;
+
+use proksi::Server;
+use socks_rs::establish::method;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Collects what the subscriber writes
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn session_span() {
+    let output = Output::default();
+    let writer = output.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    // the test runtime runs every task on this thread
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .spawn()
+    .await
+    .unwrap();
+
+    let (stream, _) = common::connect(server.local_addr(), None, &[127, 0, 0, 1], echo_port)
+        .await
+        .unwrap();
+
+    let client = stream.local_addr().unwrap();
+    drop(stream);
+    server.shutdown(Duration::from_secs(5)).await;
+
+    let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    let connected = output
+        .lines()
+        .find(|line| line.contains("Connected to"))
+        .unwrap_or_else(|| panic!("no connection event in {output}"));
+    assert!(connected.contains("session{id=1"), "{connected}");
+    assert!(connected.contains(&format!("peer={client}")), "{connected}");
+    assert!(connected.contains("command=\"CONNECT\""), "{connected}");
+    assert!(
+        connected.contains(&format!("destination=127.0.0.1:{echo_port}")),
+        "{connected}"
+    );
+    assert!(output.contains("Session closed"), "{output}");
+
+    echo_handler.abort();
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use proksi::{connection::ConnectionInfo, Server};
+use socks_rs::{establish::method, reply::reply_opt, request::command};
+use std::sync::Arc;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+
+const RULES: &str = r#"[
+    { "client": ["10.0.0.0/8"], "action": "reject" }
+]"#;
+
+#[tokio::test]
+async fn in_memory_transport() {
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![],
+    )
+    .unwrap()
+    .with_routes(serde_json::from_str(RULES).unwrap());
+    let server = Arc::new(server);
+
+    let session = |peer: &str| {
+        let (client, mut stream) = io::duplex(1024);
+        let info = ConnectionInfo::new(Some(peer.parse().unwrap()), None);
+        let server = Arc::clone(&server);
+        tokio::spawn(async move { server.serve_connection(&mut stream, info).await });
+        client
+    };
+
+    let mut client = session("192.168.0.1:4321");
+    assert_eq!(
+        request(&mut client, echo_port).await.unwrap(),
+        reply_opt::SUCCEEDED
+    );
+    common::echo(&mut client, b"batatabanana").await.unwrap();
+
+    let mut client = session("10.1.2.3:4321");
+    assert_eq!(
+        request(&mut client, echo_port).await.unwrap(),
+        reply_opt::CONNECTION_NOT_ALLOWED
+    );
+
+    echo_handler.abort();
+}
+
+async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    port: u16,
+) -> common::Result<u8> {
+    common::handshake(stream, None).await?;
+    common::request(stream, command::CONNECT, &[127, 0, 0, 1], port).await
+}
@@ -0,0 +1,84 @@
+#![cfg(unix)]
+
+mod common;
+
+use proksi::{destination::Destination, route::RouteQuery, unix::UnixListen, user::User, Server};
+use socks_rs::{establish::method, reply::reply_opt, request::command};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::UnixStream;
+
+#[tokio::test]
+async fn unix_listener() {
+    let (echo_port, echo_handler) = common::echo_server().await;
+
+    let dir = std::env::temp_dir().join(format!("proksi-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("proksi.sock");
+    let uid = std::fs::metadata(&dir).unwrap().uid();
+
+    // only the local user may reach the echo server
+    let rules = format!(
+        r#"[
+            {{ "uid": [{uid}], "port": [1], "action": "reject" }},
+            {{ "user": ["local"], "action": "direct" }},
+            {{ "action": "reject" }}
+        ]"#
+    );
+    let server = Server::new(
+        "127.0.0.1:0",
+        vec![method::NO_AUTHENTICATION_REQUIRED],
+        vec![User::new("local", "").with_uid(uid)],
+    )
+    .unwrap()
+    .with_routes(serde_json::from_str(&rules).unwrap())
+    .with_unix(UnixListen::new(&path).with_mode(0o600));
+    let routes = server.routes();
+    let server = server.spawn().await.unwrap();
+
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // the socket was staged in a private directory, gone once it is in place
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    let dst: Destination = "127.0.0.1:1".parse().unwrap();
+    let query = |uid| RouteQuery {
+        dst: &dst,
+        user: None,
+        client: None,
+        uid,
+    };
+    assert_eq!(routes.evaluate(&query(Some(uid))).unwrap().index, 0);
+    assert_eq!(routes.evaluate(&query(None)).unwrap().index, 2);
+
+    assert_eq!(
+        request(&path, echo_port).await.unwrap(),
+        reply_opt::SUCCEEDED
+    );
+    assert_eq!(
+        request(&path, 1).await.unwrap(),
+        reply_opt::CONNECTION_NOT_ALLOWED
+    );
+
+    // over TCP there are no credentials, so no user either
+    let (_, rep) = common::connect(server.local_addr(), None, &[127, 0, 0, 1], echo_port)
+        .await
+        .unwrap();
+    assert_eq!(rep, reply_opt::CONNECTION_NOT_ALLOWED);
+
+    server.shutdown_now();
+    echo_handler.abort();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+async fn request(path: &Path, port: u16) -> common::Result<u8> {
+    let mut stream = UnixStream::connect(path).await?;
+    common::handshake(&mut stream, None).await?;
+    let rep = common::request(&mut stream, command::CONNECT, &[127, 0, 0, 1], port).await?;
+
+    if rep == reply_opt::SUCCEEDED {
+        common::echo(&mut stream, b"batatabanana").await?;
+    }
+
+    Ok(rep)
+}